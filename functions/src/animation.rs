@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use image::codecs::gif::GifDecoder;
+use image::{AnimationDecoder, DynamicImage, Rgba, RgbaImage};
+use std::io::Cursor;
+
+use crate::config::Variant;
+use crate::focal::FocalHint;
+use crate::resize::{self, ResizeSpec};
+
+/// Browsers play GIF frames with a delay of 10ms or less at 100ms, as libwebp's
+/// gif2webp also does. Copied as is, they would make the WebP run far too fast.
+const MIN_FRAME_DELAY_MS: u32 = 10;
+const DEFAULT_FRAME_DELAY_MS: u32 = 100;
+/// Largest total of frame pixels kept, 256 MB of RGBA. Every frame is a full
+/// canvas and each WebP variant holds its own resized copies while encoding, so
+/// longer or larger animations are only converted as a still image.
+const MAX_TOTAL_PIXELS: u64 = 64_000_000;
+
+/// A decoded multi-frame GIF. Frames are full-canvas composites, as produced by
+/// the `image` GIF decoder.
+pub struct Animation {
+    pub frames: Vec<Frame>,
+    /// Number of times the animation plays, 0 meaning forever (WebP semantics).
+    pub loop_count: u16,
+}
+
+pub struct Frame {
+    pub image: RgbaImage,
+    pub delay_ms: u32,
+}
+
+impl Animation {
+    /// Decodes every frame of a GIF. Returns `None` for single-frame GIFs, which
+    /// go through the still image path, and for animations over the frame pixel
+    /// limit, which are converted as a still image instead.
+    pub fn decode_gif(bytes: &[u8]) -> Result<Option<Self>> {
+        let decoder = GifDecoder::new(Cursor::new(bytes)).context("Failed to read GIF")?;
+
+        let mut frames = Vec::new();
+        let mut total_pixels = 0;
+        for frame in decoder.into_frames() {
+            let frame = frame.context("Failed to decode GIF frames")?;
+            let image = frame.buffer();
+            total_pixels += image.width() as u64 * image.height() as u64;
+            if total_pixels > MAX_TOTAL_PIXELS {
+                tracing::warn!(
+                    "GIF animation exceeds {} frame pixels after {} frames, converting its first frame only",
+                    MAX_TOTAL_PIXELS,
+                    frames.len()
+                );
+                return Ok(None);
+            }
+
+            let (numerator, denominator) = frame.delay().numer_denom_ms();
+            let delay_ms = match numerator / denominator.max(1) {
+                delay_ms if delay_ms <= MIN_FRAME_DELAY_MS => DEFAULT_FRAME_DELAY_MS,
+                delay_ms => delay_ms,
+            };
+            frames.push(Frame {
+                delay_ms,
+                image: frame.into_buffer(),
+            });
+        }
+
+        if frames.len() < 2 {
+            return Ok(None);
+        }
+
+        Ok(Some(Animation {
+            frames,
+            loop_count: gif_loop_count(bytes),
+        }))
+    }
+
+    /// Resizes every frame to `spec`, cropping all of them at the window chosen
+    /// for the first frame so the crop doesn't jump between frames.
+    pub fn resize(&self, spec: &ResizeSpec, variant: &Variant, focal: Option<FocalHint>) -> Vec<Frame> {
+        let first = DynamicImage::ImageRgba8(self.frames[0].image.clone());
+        let origin = resize::crop_origin(&first, spec, variant.crop, focal);
+
+        self.frames
+            .iter()
+            .map(|frame| {
+                let image = DynamicImage::ImageRgba8(frame.image.clone());
+                let mut resized = resize::apply_at(&image, spec, Rgba(variant.background.0), origin);
+                if let Some(sharpen) = &variant.sharpen {
+                    resized = sharpen.apply(&resized);
+                }
+                Frame {
+                    image: resized.to_rgba8(),
+                    delay_ms: frame.delay_ms,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Reads the NETSCAPE2.0 looping extension and converts it to WebP semantics, the
+/// same way libwebp's gif2webp does: GIF counts repeats after the first play,
+/// WebP counts plays, and a GIF without the extension plays once.
+fn gif_loop_count(bytes: &[u8]) -> u16 {
+    const IDENTIFIERS: [&[u8]; 2] = [b"NETSCAPE2.0", b"ANIMEXTS1.0"];
+
+    for identifier in IDENTIFIERS {
+        let Some(start) = bytes.windows(identifier.len()).position(|w| w == identifier) else {
+            continue;
+        };
+        // Sub-block: size 3, id 1, then a little-endian repeat count
+        if let [3, 1, low, high, ..] = bytes[start + identifier.len()..] {
+            return match u16::from_le_bytes([low, high]) {
+                0 => 0,
+                repeats => repeats.saturating_add(1),
+            };
+        }
+    }
+
+    1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gif(repeats: Option<u16>) -> Vec<u8> {
+        let mut bytes = b"GIF89a".to_vec();
+        if let Some(repeats) = repeats {
+            bytes.extend([0x21, 0xFF, 11]);
+            bytes.extend(b"NETSCAPE2.0");
+            bytes.extend([3, 1]);
+            bytes.extend(repeats.to_le_bytes());
+            bytes.push(0);
+        }
+        bytes.push(0x3B);
+        bytes
+    }
+
+    #[test]
+    fn loop_count_counts_plays_not_repeats() {
+        // 0 repeats forever; WebP counts plays, so n repeats is n + 1 plays
+        assert_eq!(gif_loop_count(&gif(Some(0))), 0);
+        assert_eq!(gif_loop_count(&gif(Some(1))), 2);
+        assert_eq!(gif_loop_count(&gif(None)), 1);
+    }
+}
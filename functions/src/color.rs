@@ -0,0 +1,217 @@
+use anyhow::{Context, Result};
+use image::DynamicImage;
+use qcms::{DataType, Intent, Profile, Transform};
+use serde::Deserialize;
+use std::sync::OnceLock;
+
+/// Color space variants are delivered in, set with the top-level `color_profile`
+/// config key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorProfile {
+    /// Convert everything to sRGB, which needs no embedded profile.
+    #[default]
+    Srgb,
+    /// Keep wide-gamut sources in Display P3 and embed a compact P3 profile in
+    /// WebP and JPEG variants. Formats that can't carry the profile get sRGB.
+    DisplayP3,
+}
+
+/// sRGB's red, green and blue colorants adapted to the D50 ICC connection space.
+const SRGB_COLORANTS: [([u8; 4], [f64; 3]); 3] = [
+    (*b"rXYZ", [0.4361, 0.2225, 0.0139]),
+    (*b"gXYZ", [0.3851, 0.7169, 0.0971]),
+    (*b"bXYZ", [0.1431, 0.0606, 0.7141]),
+];
+
+/// Converts pixels described by the ICC profile `icc` into `target`, returning
+/// the converted image and the color space it ended up in. Untagged and sRGB
+/// sources are assumed to be sRGB and returned unchanged, so `DisplayP3` only
+/// applies to sources that actually carry a different profile.
+pub fn convert(img: &DynamicImage, icc: &[u8], target: ColorProfile) -> Result<(DynamicImage, ColorProfile)> {
+    let source = Profile::new_from_slice(icc, false).context("Unsupported or corrupt ICC profile")?;
+    if is_srgb(icc) {
+        return Ok((img.clone(), ColorProfile::Srgb));
+    }
+
+    let output = output_profile(target)?;
+    Ok((transform(img, &source, &output)?, target))
+}
+
+/// Converts an image in `from` to sRGB, for formats that can't embed a profile.
+pub fn to_srgb(img: &DynamicImage, from: ColorProfile) -> Result<DynamicImage> {
+    match from {
+        ColorProfile::Srgb => Ok(img.clone()),
+        ColorProfile::DisplayP3 => {
+            let source = output_profile(from)?;
+            transform(img, &source, &Profile::new_sRGB())
+        }
+    }
+}
+
+/// The ICC profile to embed for images in `profile`, or `None` for sRGB.
+pub fn icc_profile(profile: ColorProfile) -> Option<&'static [u8]> {
+    match profile {
+        ColorProfile::Srgb => None,
+        ColorProfile::DisplayP3 => Some(display_p3_icc()),
+    }
+}
+
+/// Recognizes the many sRGB profiles in the wild (IEC 61966-2.1, camera and
+/// editor variants) by their colorants, which is all the conversion depends on
+/// in practice.
+fn is_srgb(icc: &[u8]) -> bool {
+    SRGB_COLORANTS.iter().all(|(signature, expected)| {
+        read_xyz_tag(icc, signature).is_some_and(|actual| {
+            actual.iter().zip(expected).all(|(a, e)| (a - e).abs() < 0.005)
+        })
+    })
+}
+
+fn read_xyz_tag(icc: &[u8], signature: &[u8; 4]) -> Option<[f64; 3]> {
+    let be_u32 = |at: usize| icc.get(at..at + 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+
+    let count = be_u32(128)? as usize;
+    let entry = (0..count.min(256))
+        .map(|i| 132 + i * 12)
+        .find(|&at| icc.get(at..at + 4) == Some(signature))?;
+    let offset = be_u32(entry + 4)? as usize;
+    if icc.get(offset..offset + 4) != Some(b"XYZ ") {
+        return None;
+    }
+
+    let mut xyz = [0.0; 3];
+    for (i, value) in xyz.iter_mut().enumerate() {
+        *value = be_u32(offset + 8 + i * 4)? as i32 as f64 / 65536.0;
+    }
+    Some(xyz)
+}
+
+fn output_profile(target: ColorProfile) -> Result<Box<Profile>> {
+    match icc_profile(target) {
+        None => Ok(Profile::new_sRGB()),
+        Some(icc) => Profile::new_from_slice(icc, false).context("Failed to load the Display P3 profile"),
+    }
+}
+
+fn transform(img: &DynamicImage, source: &Profile, output: &Profile) -> Result<DynamicImage> {
+    if img.color().has_alpha() {
+        let mut pixels = img.to_rgba8();
+        Transform::new(source, output, DataType::RGBA8, Intent::Perceptual)
+            .context("ICC profile can't be applied to RGBA pixels")?
+            .apply(&mut pixels);
+        Ok(DynamicImage::ImageRgba8(pixels))
+    } else {
+        let mut pixels = img.to_rgb8();
+        Transform::new(source, output, DataType::RGB8, Intent::Perceptual)
+            .context("ICC profile can't be applied to RGB pixels")?
+            .apply(&mut pixels);
+        Ok(DynamicImage::ImageRgb8(pixels))
+    }
+}
+
+/// A minimal ICC v4 Display P3 profile (about 500 bytes): D50-adapted P3
+/// colorants with the sRGB transfer curve, as in Apple's Display P3 profile.
+fn display_p3_icc() -> &'static [u8] {
+    static PROFILE: OnceLock<Vec<u8>> = OnceLock::new();
+    PROFILE.get_or_init(|| {
+        let xyz = |[x, y, z]: [f64; 3]| {
+            let mut tag = b"XYZ \0\0\0\0".to_vec();
+            for value in [x, y, z] {
+                tag.extend(s15_fixed16(value));
+            }
+            tag
+        };
+
+        // sRGB curve as parametric function 3: (a*x + b)^g above d, c*x below
+        let mut trc = b"para\0\0\0\0\0\x03\0\0".to_vec();
+        for value in [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045] {
+            trc.extend(s15_fixed16(value));
+        }
+
+        // Bradford adaptation from D65, the P3 white point, to the D50 PCS
+        let mut chad = b"sf32\0\0\0\0".to_vec();
+        for value in [
+            1.047882, 0.022918, -0.050217,
+            0.029586, 0.990478, -0.017075,
+            -0.009247, 0.015075, 0.751678,
+        ] {
+            chad.extend(s15_fixed16(value));
+        }
+
+        build_icc(&[
+            (*b"desc", mluc("Display P3")),
+            (*b"cprt", mluc("No copyright, use freely")),
+            (*b"wtpt", xyz([0.9642, 1.0, 0.8249])),
+            (*b"rXYZ", xyz([0.515121, 0.241182, -0.001053])),
+            (*b"gXYZ", xyz([0.291977, 0.692245, 0.041885])),
+            (*b"bXYZ", xyz([0.157104, 0.066574, 0.784073])),
+            (*b"rTRC", trc.clone()),
+            (*b"gTRC", trc.clone()),
+            (*b"bTRC", trc),
+            (*b"chad", chad),
+        ])
+    })
+}
+
+/// Lays out an RGB display profile: header, tag table, then the tag data with
+/// identical tags stored once.
+fn build_icc(tags: &[([u8; 4], Vec<u8>)]) -> Vec<u8> {
+    const HEADER_SIZE: usize = 128;
+
+    let mut data: Vec<u8> = Vec::new();
+    let mut table = Vec::new();
+    let data_start = HEADER_SIZE + 4 + tags.len() * 12;
+    let mut stored: Vec<(&[u8], usize)> = Vec::new();
+    for (signature, tag) in tags {
+        let offset = match stored.iter().find(|(bytes, _)| *bytes == tag.as_slice()) {
+            Some(&(_, offset)) => offset,
+            None => {
+                let offset = data_start + data.len();
+                data.extend_from_slice(tag);
+                data.resize(data.len().next_multiple_of(4), 0);
+                stored.push((tag, offset));
+                offset
+            }
+        };
+        table.extend_from_slice(signature);
+        table.extend((offset as u32).to_be_bytes());
+        table.extend((tag.len() as u32).to_be_bytes());
+    }
+
+    let size = data_start + data.len();
+    let mut icc = Vec::with_capacity(size);
+    icc.extend((size as u32).to_be_bytes());
+    icc.extend([0; 4]); // preferred CMM
+    icc.extend([4, 0x30, 0, 0]); // version 4.3
+    icc.extend(b"mntrRGB XYZ ");
+    icc.extend([0; 12]); // creation date
+    icc.extend(b"acsp");
+    icc.extend([0; 24]); // platform, flags, manufacturer, model, attributes
+    icc.extend([0; 4]); // perceptual intent
+    for value in [0.9642, 1.0, 0.8249] {
+        icc.extend(s15_fixed16(value)); // D50 illuminant
+    }
+    icc.resize(HEADER_SIZE, 0); // creator, profile ID and reserved bytes
+    icc.extend((tags.len() as u32).to_be_bytes());
+    icc.extend(table);
+    icc.extend(data);
+    icc
+}
+
+/// A multi-localized Unicode tag with a single en-US string.
+fn mluc(text: &str) -> Vec<u8> {
+    let utf16: Vec<u8> = text.encode_utf16().flat_map(u16::to_be_bytes).collect();
+    let mut tag = b"mluc\0\0\0\0".to_vec();
+    tag.extend(1u32.to_be_bytes()); // record count
+    tag.extend(12u32.to_be_bytes()); // record size
+    tag.extend(b"enUS");
+    tag.extend((utf16.len() as u32).to_be_bytes());
+    tag.extend(28u32.to_be_bytes()); // string offset from the tag start
+    tag.extend(utf16);
+    tag
+}
+
+fn s15_fixed16(value: f64) -> [u8; 4] {
+    ((value * 65536.0).round() as i32).to_be_bytes()
+}
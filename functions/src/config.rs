@@ -0,0 +1,395 @@
+use anyhow::{bail, Context, Result};
+use aws_sdk_s3::Client as S3Client;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::color::ColorProfile;
+use crate::duplicates::DuplicatePolicy;
+use crate::metadata::MetadataPolicy;
+use crate::resize::{CropStrategy, Fit, ResizeSpec};
+use crate::sharpen::Sharpen;
+
+const DEFAULT_CONFIG: &str = include_str!("../variants.toml");
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// How to treat variants wider than the source. Variants may override it.
+    #[serde(default)]
+    pub upscale: UpscalePolicy,
+    /// Color space sources with an embedded ICC profile are converted to.
+    #[serde(default)]
+    pub color_profile: ColorProfile,
+    /// What to do with near-duplicates of sources already uploaded under the same prefix.
+    #[serde(default)]
+    pub duplicates: DuplicatePolicy,
+    /// How variant keys are derived from the source key.
+    #[serde(default)]
+    pub naming: Naming,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Variant {
+    pub name: String,
+    /// Target width in pixels. Variants without a width keep the source dimensions.
+    #[serde(default)]
+    pub width: Option<u32>,
+    /// Target height in pixels. Without it the height follows the source aspect ratio.
+    #[serde(default)]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub fit: Fit,
+    /// Placement of the `cover` crop window when the source has no focal hint.
+    #[serde(default)]
+    pub crop: CropStrategy,
+    /// Padding color for `contain`, and the color JPEG output is flattened onto, as
+    /// `#rrggbb` or `#rrggbbaa`. Defaults to transparent.
+    #[serde(default)]
+    pub background: HexColor,
+    #[serde(default)]
+    pub format: OutputFormat,
+    /// Encoder quality from 0 to 100. Ignored by lossless encoders.
+    #[serde(default)]
+    pub quality: Option<u8>,
+    /// Encode WebP losslessly instead of lossy.
+    #[serde(default)]
+    pub lossless: bool,
+    /// WebP compression effort from 0 (fastest) to 6 (smallest).
+    #[serde(default)]
+    pub method: Option<u8>,
+    /// Quality of the alpha channel from 0 to 100, for lossy formats.
+    #[serde(default)]
+    pub alpha_quality: Option<u8>,
+    /// Appended to the source key stem, e.g. `photo.jpg` + `-480` -> `photo-480.webp`.
+    #[serde(default)]
+    pub suffix: String,
+    /// Overrides the config-wide `upscale` policy for this variant.
+    #[serde(default)]
+    pub upscale: Option<UpscalePolicy>,
+    /// Source EXIF and XMP metadata to carry over. Stripped by default.
+    #[serde(default)]
+    pub metadata: MetadataPolicy,
+    /// Resize in linear light instead of sRGB. Defaults to on for lossy (photographic)
+    /// variants and off for lossless WebP and PNG.
+    #[serde(default)]
+    pub linear_light: Option<bool>,
+    /// Unsharp mask applied after resizing. Off by default, and never applied to
+    /// variants that keep the source dimensions.
+    #[serde(default)]
+    pub sharpen: Option<Sharpen>,
+    /// Largest acceptable output size. Lowers the quality, then the width, until
+    /// the variant fits. Lossy formats only.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Picks the lowest quality whose output is within this DSSIM of the resized
+    /// image, using `quality` as the ceiling. WebP and JPEG only.
+    #[serde(default)]
+    pub max_dssim: Option<f64>,
+    /// Which sources the variant is produced for, by whether they have transparent pixels.
+    #[serde(default)]
+    pub sources: Sources,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpscalePolicy {
+    /// Don't produce variants wider than the source.
+    #[default]
+    Skip,
+    /// Produce them at the source width instead.
+    Clamp,
+    /// Upscale the source to the requested width.
+    Allow,
+}
+
+impl UpscalePolicy {
+    /// Returns the spec to resize with, or `None` if the variant should be skipped.
+    /// A spec upscales when the source has to be scaled up to fit its box the way
+    /// its `fit` does, which for `exact` and `contain` can happen with a box larger
+    /// than the source along one edge only. Clamping shrinks the box by the same
+    /// factor, so the source is used at its own resolution.
+    pub fn apply(self, spec: ResizeSpec, source: (u32, u32)) -> Option<ResizeSpec> {
+        let scale = spec.scale_factor(source);
+        if scale <= 1.0 {
+            return Some(spec);
+        }
+
+        match self {
+            UpscalePolicy::Skip => None,
+            UpscalePolicy::Allow => Some(spec),
+            UpscalePolicy::Clamp => Some(spec.shrunk(1.0 / scale)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sources {
+    #[default]
+    All,
+    /// Only sources without transparent pixels, e.g. for JPEG fallbacks.
+    Opaque,
+    /// Only sources with transparent pixels, e.g. for PNG fallbacks.
+    Transparent,
+}
+
+impl Sources {
+    pub fn includes(self, transparent: bool) -> bool {
+        match self {
+            Sources::All => true,
+            Sources::Opaque => !transparent,
+            Sources::Transparent => transparent,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Naming {
+    /// `photo-480.webp`. Keys stay the same when the source is replaced.
+    #[default]
+    Plain,
+    /// `photo.3f9a1c-480.webp`. Keys embed a hash of the source content, so a
+    /// replaced source gets new URLs instead of year-long stale cache entries.
+    Fingerprinted,
+}
+
+/// An RGBA color written as `#rrggbb` or `#rrggbbaa` in the config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct HexColor(pub [u8; 4]);
+
+impl TryFrom<String> for HexColor {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        let hex = value.trim_start_matches('#');
+        if !matches!(hex.len(), 6 | 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Expected #rrggbb or #rrggbbaa, got '{}'", value));
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(0);
+        let alpha = if hex.len() == 8 { channel(6) } else { 255 };
+        Ok(HexColor([channel(0), channel(2), channel(4), alpha]))
+    }
+}
+
+impl Variant {
+    pub fn resize_spec(&self) -> Option<ResizeSpec> {
+        self.width.map(|width| ResizeSpec {
+            width,
+            height: self.height,
+            fit: self.fit,
+            // Lossy variants are assumed to be photographs; lossless WebP and PNG
+            // are usually graphics, where sRGB filtering is what designers expect
+            linear_light: self.linear_light.unwrap_or(self.is_lossy()),
+        })
+    }
+
+    /// Whether the variant's encoder has a quality setting.
+    pub fn is_lossy(&self) -> bool {
+        match self.format {
+            OutputFormat::WebP => !self.lossless,
+            OutputFormat::Avif | OutputFormat::Jpeg => true,
+            OutputFormat::Png => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    WebP,
+    Avif,
+    Jpeg,
+    Png,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+        }
+    }
+
+    /// Whether variants in this format carry an embedded ICC profile. The others
+    /// are always delivered in sRGB.
+    pub fn embeds_icc_profile(self) -> bool {
+        matches!(self, OutputFormat::WebP | OutputFormat::Jpeg)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+        }
+    }
+}
+
+impl Config {
+    pub fn upscale_policy(&self, variant: &Variant) -> UpscalePolicy {
+        variant.upscale.unwrap_or(self.upscale)
+    }
+
+    /// Loads the variant configuration, in order of precedence, from the inline
+    /// `VARIANTS_CONFIG` document, the file or `s3://` object named by
+    /// `VARIANTS_CONFIG_LOCATION`, or the bundled `variants.toml`.
+    pub async fn load(s3_client: &S3Client) -> Result<Self> {
+        if let Some(inline) = non_empty_env("VARIANTS_CONFIG") {
+            return Self::parse(&inline, None).context("Invalid VARIANTS_CONFIG");
+        }
+
+        if let Some(location) = non_empty_env("VARIANTS_CONFIG_LOCATION") {
+            let text = read_location(s3_client, &location).await?;
+            let hint = location.rsplit('.').next();
+            return Self::parse(&text, hint)
+                .with_context(|| format!("Invalid variants config at {}", location));
+        }
+
+        Self::parse(DEFAULT_CONFIG, Some("toml")).context("Invalid bundled variants.toml")
+    }
+
+    fn parse(text: &str, extension_hint: Option<&str>) -> Result<Self> {
+        let is_json = match extension_hint {
+            Some(ext) if ext.eq_ignore_ascii_case("json") => true,
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => false,
+            _ => text.trim_start().starts_with('{'),
+        };
+
+        let config: Config = if is_json {
+            serde_json::from_str(text).context("Failed to parse JSON variants config")?
+        } else {
+            toml::from_str(text).context("Failed to parse TOML variants config")?
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.variants.is_empty() {
+            bail!("At least one variant must be configured");
+        }
+
+        let mut names = HashSet::new();
+        let mut outputs = HashSet::new();
+        for variant in &self.variants {
+            if !names.insert(variant.name.as_str()) {
+                bail!("Duplicate variant name '{}'", variant.name);
+            }
+            if !outputs.insert((variant.suffix.as_str(), variant.format)) {
+                bail!(
+                    "Variant '{}' writes the same key as another variant (suffix '{}', format {})",
+                    variant.name,
+                    variant.suffix,
+                    variant.format.extension()
+                );
+            }
+            if variant.width == Some(0) || variant.height == Some(0) {
+                bail!("Variant '{}' has a width or height of 0", variant.name);
+            }
+            if variant.height.is_some() && variant.width.is_none() {
+                bail!("Variant '{}' sets a height without a width", variant.name);
+            }
+            if matches!(variant.quality, Some(q) if q > 100) {
+                bail!("Variant '{}' quality must be between 0 and 100", variant.name);
+            }
+            if matches!(variant.alpha_quality, Some(q) if q > 100) {
+                bail!("Variant '{}' alpha_quality must be between 0 and 100", variant.name);
+            }
+            if matches!(variant.method, Some(m) if m > 6) {
+                bail!("Variant '{}' method must be between 0 and 6", variant.name);
+            }
+            if variant.max_bytes.is_some() && !variant.is_lossy() {
+                bail!("Variant '{}' max_bytes needs a lossy format (webp, avif or jpeg)", variant.name);
+            }
+            if let Some(max_dssim) = variant.max_dssim {
+                if !variant.is_lossy() || variant.format == OutputFormat::Avif {
+                    bail!("Variant '{}' max_dssim needs a lossy webp or jpeg format", variant.name);
+                }
+                if !(max_dssim > 0.0 && max_dssim.is_finite()) {
+                    bail!("Variant '{}' max_dssim must be greater than 0", variant.name);
+                }
+            }
+            if variant.suffix.contains('/') {
+                bail!("Variant '{}' suffix must not contain '/'", variant.name);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn non_empty_env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+async fn read_location(s3_client: &S3Client, location: &str) -> Result<String> {
+    let bytes = match location.strip_prefix("s3://") {
+        Some(path) => {
+            let (bucket_name, key) = path
+                .split_once('/')
+                .with_context(|| format!("Expected s3://bucket/key, got {}", location))?;
+            crate::download_object(s3_client, bucket_name, key).await?.body
+        }
+        None => std::fs::read(location)
+            .with_context(|| format!("Failed to read variants config file {}", location))?,
+    };
+
+    String::from_utf8(bytes).context("Variants config is not valid UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: (u32, u32) = (3000, 1000);
+
+    fn spec(width: u32, height: u32, fit: Fit) -> ResizeSpec {
+        ResizeSpec { width, height: Some(height), fit, linear_light: false }
+    }
+
+    #[test]
+    fn downscales_are_kept_by_every_policy() {
+        // A box larger than the source along one edge is still a downscale when
+        // the fit scales by the smaller axis
+        for spec in [spec(1920, 1080, Fit::Exact), spec(1920, 1080, Fit::Contain), spec(1500, 500, Fit::Cover), spec(1500, 500, Fit::Fill)] {
+            for policy in [UpscalePolicy::Skip, UpscalePolicy::Clamp, UpscalePolicy::Allow] {
+                assert_eq!(policy.apply(spec, SOURCE), Some(spec), "{:?} {:?}", policy, spec.fit);
+            }
+        }
+    }
+
+    #[test]
+    fn upscales_follow_the_policy() {
+        let cases = [
+            (spec(6000, 4000, Fit::Exact), spec(3000, 2000, Fit::Exact)),
+            (spec(6000, 4000, Fit::Contain), spec(3000, 2000, Fit::Contain)),
+            // Cover and fill scale by the larger axis, so one edge over the source is enough
+            (spec(1920, 1920, Fit::Cover), spec(1000, 1000, Fit::Cover)),
+            (spec(4000, 500, Fit::Fill), spec(3000, 375, Fit::Fill)),
+        ];
+        for (requested, clamped) in cases {
+            assert_eq!(UpscalePolicy::Skip.apply(requested, SOURCE), None, "{:?}", requested.fit);
+            assert_eq!(UpscalePolicy::Clamp.apply(requested, SOURCE), Some(clamped), "{:?}", requested.fit);
+            assert_eq!(UpscalePolicy::Allow.apply(requested, SOURCE), Some(requested), "{:?}", requested.fit);
+        }
+    }
+
+    #[test]
+    fn width_only_specs_compare_widths() {
+        let requested = ResizeSpec { width: 4000, height: None, fit: Fit::Exact, linear_light: false };
+        assert_eq!(UpscalePolicy::Skip.apply(requested, SOURCE), None);
+        assert_eq!(UpscalePolicy::Clamp.apply(requested, SOURCE).map(|spec| spec.width), Some(3000));
+        let downscale = ResizeSpec { width: 1920, ..requested };
+        assert_eq!(UpscalePolicy::Skip.apply(downscale, SOURCE), Some(downscale));
+    }
+}
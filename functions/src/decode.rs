@@ -0,0 +1,170 @@
+use anyhow::{bail, Context, Result};
+use image::codecs::{jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder, webp::WebPDecoder};
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageResult};
+use std::io::Cursor;
+
+use crate::animation::Animation;
+use crate::color::ColorProfile;
+use crate::config::OutputFormat;
+use crate::svg::{self, VectorImage};
+
+/// A decoded upload. `image` is always a still rendition of the source; animated
+/// and vector sources also keep what variants are rendered from.
+pub struct DecodedSource {
+    pub image: DynamicImage,
+    pub animation: Option<Animation>,
+    pub vector: Option<VectorImage>,
+    /// ICC profile embedded in the source, describing the pixels of `image`.
+    pub icc_profile: Option<Vec<u8>>,
+    /// Color space `image` is in once its ICC profile has been applied.
+    pub color: ColorProfile,
+}
+
+impl DecodedSource {
+    fn still(image: DynamicImage, icc_profile: Option<Vec<u8>>) -> Self {
+        DecodedSource {
+            image,
+            animation: None,
+            vector: None,
+            icc_profile,
+            color: ColorProfile::Srgb,
+        }
+    }
+}
+
+/// Decodes an uploaded source, dispatching the formats `image` can't read to
+/// their own decoders.
+pub fn decode(bytes: &[u8]) -> Result<DecodedSource> {
+    if is_heif(bytes) {
+        let (image, icc_profile) = decode_heif(bytes)?;
+        return Ok(DecodedSource::still(image, icc_profile));
+    }
+
+    if svg::is_svg(bytes) {
+        let vector = VectorImage::parse(bytes)?;
+        return Ok(DecodedSource {
+            image: vector.render(1.0)?,
+            animation: None,
+            vector: Some(vector),
+            icc_profile: None,
+            color: ColorProfile::Srgb,
+        });
+    }
+
+    // RAW files mostly share TIFF's signature, so anything rawloader rejects
+    // is handed to the regular decoders
+    #[cfg(feature = "raw")]
+    if crate::raw::is_raw(bytes) {
+        match crate::raw::decode(bytes) {
+            Ok(image) => return Ok(DecodedSource::still(image, None)),
+            Err(e) => tracing::debug!("Not decoding as RAW: {}", e),
+        }
+    }
+
+    let (image, icc_profile) = load_with_profile(bytes).context("Unsupported or corrupt image")?;
+
+    // Animated GIFs keep every frame for WebP variants; other formats use the first frame
+    let animation = if image::guess_format(bytes).ok() == Some(ImageFormat::Gif) {
+        Animation::decode_gif(bytes).unwrap_or_else(|e| {
+            tracing::warn!("Treating GIF as a still image: {}", e);
+            None
+        })
+    } else {
+        None
+    };
+
+    Ok(DecodedSource {
+        image,
+        animation,
+        vector: None,
+        icc_profile,
+        color: ColorProfile::Srgb,
+    })
+}
+
+/// Decodes with `image`, also reading the embedded ICC profile for the formats
+/// whose decoders expose it.
+fn load_with_profile(bytes: &[u8]) -> ImageResult<(DynamicImage, Option<Vec<u8>>)> {
+    fn read<'a>(mut decoder: impl ImageDecoder<'a>) -> ImageResult<(DynamicImage, Option<Vec<u8>>)> {
+        let icc_profile = decoder.icc_profile();
+        Ok((DynamicImage::from_decoder(decoder)?, icc_profile))
+    }
+
+    let format = image::guess_format(bytes)?;
+    let cursor = Cursor::new(bytes);
+    match format {
+        ImageFormat::Jpeg => read(JpegDecoder::new(cursor)?),
+        ImageFormat::Png => read(PngDecoder::new(cursor)?),
+        ImageFormat::WebP => read(WebPDecoder::new(cursor)?),
+        ImageFormat::Tiff => read(TiffDecoder::new(cursor)?),
+        _ => Ok((image::load_from_memory_with_format(bytes, format)?, None)),
+    }
+}
+
+/// Decodes an encoded variant back to pixels, to measure what the encoder lost.
+/// AVIF isn't supported, since decoding AV1 needs a C decoder (dav1d).
+pub fn decode_output(bytes: &[u8], format: OutputFormat) -> Result<DynamicImage> {
+    let image_format = match format {
+        OutputFormat::WebP => ImageFormat::WebP,
+        OutputFormat::Jpeg => ImageFormat::Jpeg,
+        OutputFormat::Png => ImageFormat::Png,
+        OutputFormat::Avif => bail!("AVIF variants can't be decoded"),
+    };
+    image::load_from_memory_with_format(bytes, image_format)
+        .with_context(|| format!("Failed to decode {} variant", format.extension()))
+}
+
+/// HEIF files are ISO BMFF containers whose `ftyp` box names a HEIF brand.
+pub fn is_heif(bytes: &[u8]) -> bool {
+    bytes.len() >= 12
+        && &bytes[4..8] == b"ftyp"
+        && matches!(
+            &bytes[8..12],
+            b"heic" | b"heix" | b"hevc" | b"hevx" | b"heim" | b"heis" | b"mif1" | b"msf1"
+        )
+}
+
+#[cfg(feature = "heif")]
+fn decode_heif(bytes: &[u8]) -> Result<(DynamicImage, Option<Vec<u8>>)> {
+    use image::{RgbImage, RgbaImage};
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let lib_heif = LibHeif::new();
+    let context = HeifContext::read_from_bytes(bytes).context("Failed to read HEIF container")?;
+    let handle = context
+        .primary_image_handle()
+        .context("HEIF file has no primary image")?;
+
+    let icc_profile = handle.color_profile_raw().map(|profile| profile.data);
+    let has_alpha = handle.has_alpha_channel();
+    let chroma = if has_alpha { RgbChroma::Rgba } else { RgbChroma::Rgb };
+    let image = lib_heif
+        .decode(&handle, ColorSpace::Rgb(chroma), None)
+        .context("Failed to decode HEIF image")?;
+
+    let planes = image.planes();
+    let plane = planes
+        .interleaved
+        .context("Decoded HEIF image has no interleaved plane")?;
+
+    // Rows may be padded, so copy them out one at a time
+    let channels = if has_alpha { 4 } else { 3 };
+    let row_len = plane.width as usize * channels;
+    let mut pixels = Vec::with_capacity(row_len * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_len]);
+    }
+
+    let img = if has_alpha {
+        RgbaImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgba8)
+    } else {
+        RgbImage::from_raw(plane.width, plane.height, pixels).map(DynamicImage::ImageRgb8)
+    };
+    let img = img.context("Decoded HEIF plane doesn't match its dimensions")?;
+    Ok((img, icc_profile))
+}
+
+#[cfg(not(feature = "heif"))]
+fn decode_heif(_bytes: &[u8]) -> Result<(DynamicImage, Option<Vec<u8>>)> {
+    anyhow::bail!("HEIF support is not enabled; build with the `heif` feature")
+}
@@ -0,0 +1,131 @@
+use anyhow::{bail, Context, Result};
+use aws_sdk_s3::{primitives::ByteStream, Client as S3Client};
+use serde::{Deserialize, Serialize};
+
+use crate::phash::PerceptualHash;
+
+/// Index of every source under a prefix, next to the sources themselves.
+const INDEX_NAME: &str = ".duplicates.json";
+/// Attempts at updating the index when other uploads keep changing it first.
+const MAX_ATTEMPTS: usize = 5;
+/// Sources kept in an index. Past it the least recently uploaded are dropped, so
+/// the object every upload under the prefix reads and rewrites stays small.
+const MAX_SOURCES: usize = 1000;
+
+/// What happens when an upload is a near-duplicate of a source already indexed
+/// under the same prefix, set with the top-level `duplicates` config key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    /// Don't hash sources or keep an index.
+    Off,
+    /// Log the duplicate and name it in the manifest, but encode as usual.
+    #[default]
+    Flag,
+    /// Also copy the other source's variants instead of encoding them, when both
+    /// uploads have identical contents. Near-duplicates that differ, such as
+    /// an exposure edit, are only flagged.
+    Reuse,
+}
+
+/// A source as recorded in the index.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedSource {
+    pub key: String,
+    #[serde(flatten)]
+    pub hash: PerceptualHash,
+    /// Hex SHA-256 of the source object.
+    pub sha256: String,
+    /// Whether the upload had a focal hint, which changes how its variants are cropped.
+    pub focal_hint: bool,
+    /// Content fingerprint in the source's variant keys, if they have one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+}
+
+impl IndexedSource {
+    /// Whether this source's variants can stand in for `other`'s: they must have
+    /// been generated from the same bytes and cropped the same way.
+    pub fn can_reuse_for(&self, other: &IndexedSource) -> bool {
+        self.sha256 == other.sha256 && !self.focal_hint && !other.focal_hint
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    sources: Vec<IndexedSource>,
+}
+
+/// Adds `source` to the index of its prefix, replacing any earlier entry for the
+/// same key, and returns the closest near-duplicate already indexed. An identical
+/// source counts as the closest. Concurrent
+/// uploads are handled with conditional writes: the index is read again and the
+/// update retried when another upload wrote it in the meantime.
+pub async fn register(s3_client: &S3Client, bucket_name: &str, source: &IndexedSource) -> Result<Option<IndexedSource>> {
+    let index_key = index_key(&source.key);
+    for _ in 0..MAX_ATTEMPTS {
+        let (mut index, etag) = read_index(s3_client, bucket_name, &index_key).await?;
+
+        let duplicate = index
+            .sources
+            .iter()
+            .filter(|other| other.key != source.key && other.hash.is_near(&source.hash))
+            .min_by_key(|other| (!other.can_reuse_for(source), other.hash.distance(&source.hash)))
+            .cloned();
+
+        index.sources.retain(|other| other.key != source.key);
+        index.sources.push(source.clone());
+        let excess = index.sources.len().saturating_sub(MAX_SOURCES);
+        index.sources.drain(..excess);
+        let body = serde_json::to_vec(&index).context("Failed to serialize duplicate index")?;
+
+        let request = s3_client
+            .put_object()
+            .bucket(bucket_name)
+            .key(&index_key)
+            .body(ByteStream::from(body))
+            .content_type("application/json")
+            .metadata(crate::GENERATED_FROM_METADATA, urlencoding::encode(&source.key));
+        let request = match &etag {
+            Some(etag) => request.if_match(etag),
+            None => request.if_none_match("*"),
+        };
+
+        match request.send().await {
+            Ok(_) => return Ok(duplicate),
+            // 412: changed since it was read, 409: a concurrent conditional write
+            Err(e) if matches!(e.raw_response().map(|r| r.status().as_u16()), Some(409 | 412)) => continue,
+            Err(e) => return Err(e).context("Failed to write duplicate index"),
+        }
+    }
+
+    bail!("Duplicate index {} kept changing during {} attempts", index_key, MAX_ATTEMPTS)
+}
+
+/// `photos/2024/cat.jpg` -> `photos/2024/.duplicates.json`
+fn index_key(source_key: &str) -> String {
+    match source_key.rfind('/') {
+        Some(slash) => format!("{}/{}", &source_key[..slash], INDEX_NAME),
+        None => INDEX_NAME.to_string(),
+    }
+}
+
+/// Returns the index and its ETag, or an empty index if there is none yet.
+async fn read_index(s3_client: &S3Client, bucket_name: &str, key: &str) -> Result<(Index, Option<String>)> {
+    let response = match s3_client.get_object().bucket(bucket_name).key(key).send().await {
+        Ok(response) => response,
+        Err(e) if e.as_service_error().is_some_and(|error| error.is_no_such_key()) => {
+            return Ok((Index::default(), None));
+        }
+        Err(e) => return Err(e).context("Failed to read duplicate index"),
+    };
+
+    let etag = response.e_tag().map(str::to_string);
+    let body = response.body.collect().await.context("Failed to read duplicate index body")?;
+    // A corrupt index is started over rather than blocking every upload under the prefix
+    let index = serde_json::from_slice(&body.into_bytes()).unwrap_or_else(|e| {
+        tracing::warn!("Replacing corrupt duplicate index {}: {}", key, e);
+        Index::default()
+    });
+    Ok((index, etag))
+}
@@ -0,0 +1,601 @@
+use anyhow::{anyhow, bail, Context, Result};
+use image::{imageops, DynamicImage, ImageFormat, Rgba, RgbaImage};
+use ravif::{Img, RGB8, RGBA8};
+use std::io::Cursor;
+
+use crate::animation::Frame;
+use crate::color::{self, ColorProfile};
+use crate::config::{OutputFormat, Variant};
+use crate::metadata::Metadata;
+
+const WEBP_DEFAULT_QUALITY: u8 = 80;
+/// libwebp's compression method from 0 (fastest) to 6 (smallest).
+const WEBP_DEFAULT_METHOD: u8 = 4;
+const AVIF_DEFAULT_QUALITY: u8 = 70;
+/// rav1e preset from 1 (slowest) to 10. Anything slower doesn't fit the Lambda timeout.
+const AVIF_SPEED: u8 = 6;
+const JPEG_DEFAULT_QUALITY: u8 = 82;
+/// oxipng optimization level from 0 to 6. Higher levels cost seconds per image for
+/// a percent or two of savings.
+const PNG_OPTIMIZATION_LEVEL: u8 = 2;
+
+/// Encodes an already resized image in the variant's output format. Images that
+/// aren't sRGB are tagged with the ICC profile of their `color` space, which
+/// only WebP and JPEG support. `metadata` is embedded in WebP, AVIF and JPEG;
+/// PNG variants never carry metadata.
+pub fn encode(img: &DynamicImage, variant: &Variant, color: ColorProfile, metadata: &Metadata) -> Result<Vec<u8>> {
+    let icc_profile = color::icc_profile(color);
+    if icc_profile.is_some() && !variant.format.embeds_icc_profile() {
+        bail!("{} variants can't carry an ICC profile", variant.format.extension());
+    }
+
+    match variant.format {
+        OutputFormat::WebP => encode_webp(img, variant, icc_profile, metadata),
+        OutputFormat::Avif => encode_avif(img, variant, metadata),
+        OutputFormat::Jpeg => encode_jpeg(img, variant, icc_profile, metadata),
+        OutputFormat::Png => encode_png(img),
+    }
+}
+
+/// Encoder quality a variant is encoded at by default, or `None` for lossless
+/// variants.
+pub fn quality(variant: &Variant) -> Option<u8> {
+    match variant.format {
+        OutputFormat::WebP if variant.lossless => None,
+        OutputFormat::WebP => Some(variant.quality.unwrap_or(WEBP_DEFAULT_QUALITY)),
+        OutputFormat::Avif => Some(variant.quality.unwrap_or(AVIF_DEFAULT_QUALITY)),
+        OutputFormat::Jpeg => Some(variant.quality.unwrap_or(JPEG_DEFAULT_QUALITY)),
+        OutputFormat::Png => None,
+    }
+}
+
+fn webp_config(variant: &Variant) -> Result<webp::WebPConfig> {
+    let mut config = webp::WebPConfig::new()
+        .map_err(|_| anyhow!("Failed to initialize WebP encoder config"))?;
+    config.lossless = variant.lossless as i32;
+    config.quality = variant.quality.unwrap_or(WEBP_DEFAULT_QUALITY) as f32;
+    config.method = variant.method.unwrap_or(WEBP_DEFAULT_METHOD) as i32;
+    config.alpha_quality = variant.alpha_quality.unwrap_or(100) as i32;
+    Ok(config)
+}
+
+fn encode_webp(img: &DynamicImage, variant: &Variant, icc_profile: Option<&[u8]>, metadata: &Metadata) -> Result<Vec<u8>> {
+    let config = webp_config(variant)?;
+
+    let encoded = if img.color().has_alpha() {
+        let pixels = img.to_rgba8();
+        webp::Encoder::from_rgba(&pixels, img.width(), img.height()).encode_advanced(&config)
+    } else {
+        let pixels = img.to_rgb8();
+        webp::Encoder::from_rgb(&pixels, img.width(), img.height()).encode_advanced(&config)
+    };
+
+    let encoded = encoded.map_err(|e| anyhow!("Failed to encode image as WebP: {:?}", e))?;
+    if icc_profile.is_none() && metadata.exif.is_none() && metadata.xmp.is_none() {
+        return Ok(encoded.to_vec());
+    }
+    extend_webp(&encoded, img, icc_profile, metadata)
+}
+
+/// The `webp` crate can't attach an ICC profile or metadata, so this rewrites a
+/// still WebP into the extended format: a VP8X header flagging what follows,
+/// the ICCP chunk, the original image chunks, then the EXIF and XMP chunks.
+fn extend_webp(webp: &[u8], img: &DynamicImage, icc_profile: Option<&[u8]>, metadata: &Metadata) -> Result<Vec<u8>> {
+    const ICC_FLAG: u8 = 0x20;
+    const ALPHA_FLAG: u8 = 0x10;
+    const EXIF_FLAG: u8 = 0x08;
+    const XMP_FLAG: u8 = 0x04;
+
+    if webp.len() < 12 || &webp[..4] != b"RIFF" || &webp[8..12] != b"WEBP" {
+        bail!("Encoder produced an invalid WebP file");
+    }
+    let mut chunks = &webp[12..];
+    // libwebp only writes VP8X for lossy images with alpha, followed by ALPH and VP8
+    let mut flags = if img.color().has_alpha() { ALPHA_FLAG } else { 0 };
+    if chunks.starts_with(b"VP8X") && chunks.len() >= 18 {
+        flags |= chunks[8];
+        chunks = &chunks[18..];
+    }
+
+    if icc_profile.is_some() {
+        flags |= ICC_FLAG;
+    }
+    if metadata.exif.is_some() {
+        flags |= EXIF_FLAG;
+    }
+    if metadata.xmp.is_some() {
+        flags |= XMP_FLAG;
+    }
+
+    let write_chunk = |body: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]| {
+        body.extend(fourcc);
+        body.extend((data.len() as u32).to_le_bytes());
+        body.extend(data);
+        if data.len() % 2 == 1 {
+            body.push(0);
+        }
+    };
+
+    let mut header = vec![flags, 0, 0, 0];
+    header.extend(&(img.width() - 1).to_le_bytes()[..3]);
+    header.extend(&(img.height() - 1).to_le_bytes()[..3]);
+
+    let mut body = b"WEBP".to_vec();
+    write_chunk(&mut body, b"VP8X", &header);
+    if let Some(icc_profile) = icc_profile {
+        write_chunk(&mut body, b"ICCP", icc_profile);
+    }
+    body.extend(chunks);
+    if let Some(exif) = &metadata.exif {
+        write_chunk(&mut body, b"EXIF", exif);
+    }
+    if let Some(xmp) = &metadata.xmp {
+        write_chunk(&mut body, b"XMP ", xmp.as_bytes());
+    }
+
+    let mut riff = b"RIFF".to_vec();
+    riff.extend((body.len() as u32).to_le_bytes());
+    riff.extend(body);
+    Ok(riff)
+}
+
+/// Encodes already resized frames as an animated WebP.
+pub fn encode_animated_webp(frames: &[Frame], loop_count: u16, variant: &Variant) -> Result<Vec<u8>> {
+    let config = webp_config(variant)?;
+    let (width, height) = frames[0].image.dimensions();
+
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(loop_count as i32);
+
+    let mut timestamp = 0;
+    for frame in frames {
+        encoder.add_frame(webp::AnimFrame::from_rgba(frame.image.as_raw(), width, height, timestamp as i32));
+        timestamp += frame.delay_ms;
+    }
+
+    let encoded = encoder
+        .try_encode()
+        .map_err(|e| anyhow!("Failed to encode animated WebP: {:?}", e))?;
+    let mut encoded = encoded.to_vec();
+    set_total_duration(&mut encoded, timestamp);
+    Ok(encoded)
+}
+
+/// libwebp can't be told when the last frame ends through the `webp` crate and
+/// gives it the average frame duration instead. Rewrites the last ANMF chunk's
+/// duration so the animation runs for `total_ms` like the source.
+fn set_total_duration(webp: &mut [u8], total_ms: u32) {
+    // (offset of the duration field, duration) for every ANMF chunk
+    let mut frames = Vec::new();
+
+    // RIFF header is "RIFF", size, "WEBP"; chunks are fourcc, size, even-padded payload
+    let mut offset = 12;
+    while offset + 8 <= webp.len() {
+        let size = u32::from_le_bytes([webp[offset + 4], webp[offset + 5], webp[offset + 6], webp[offset + 7]]) as usize;
+        // ANMF payload starts with X, Y, width - 1, height - 1 and duration, 24 bits each
+        let at = offset + 8 + 12;
+        if &webp[offset..offset + 4] == b"ANMF" && at + 3 <= webp.len() {
+            frames.push((at, u32::from_le_bytes([webp[at], webp[at + 1], webp[at + 2], 0])));
+        }
+        offset += 8 + size + (size & 1);
+    }
+
+    if let Some((&(at, _), previous)) = frames.split_last() {
+        let elapsed: u32 = previous.iter().map(|&(_, duration)| duration).sum();
+        let duration = total_ms.saturating_sub(elapsed).clamp(1, 0xFF_FFFF);
+        webp[at..at + 3].copy_from_slice(&duration.to_le_bytes()[..3]);
+    }
+}
+
+fn encode_avif(img: &DynamicImage, variant: &Variant, metadata: &Metadata) -> Result<Vec<u8>> {
+    let width = img.width() as usize;
+    let height = img.height() as usize;
+    let encoder = ravif::Encoder::new()
+        .with_quality(variant.quality.unwrap_or(AVIF_DEFAULT_QUALITY) as f32)
+        .with_alpha_quality(variant.alpha_quality.unwrap_or(100) as f32)
+        .with_speed(AVIF_SPEED);
+
+    let encoded = if img.color().has_alpha() {
+        let pixels: Vec<RGBA8> = img
+            .to_rgba8()
+            .pixels()
+            .map(|p| RGBA8::new(p[0], p[1], p[2], p[3]))
+            .collect();
+        encoder.encode_rgba(Img::new(&pixels, width, height))
+    } else {
+        let pixels: Vec<RGB8> = img
+            .to_rgb8()
+            .pixels()
+            .map(|p| RGB8::new(p[0], p[1], p[2]))
+            .collect();
+        encoder.encode_rgb(Img::new(&pixels, width, height))
+    };
+    let encoded = encoded.context("Failed to encode image as AVIF")?;
+
+    let file = match &metadata.exif {
+        Some(exif) => add_avif_exif(&encoded, img, exif)?,
+        None => encoded.avif_file,
+    };
+    match &metadata.xmp {
+        Some(xmp) => add_avif_xmp(&file, xmp.as_bytes()),
+        None => Ok(file),
+    }
+}
+
+/// ravif can't attach EXIF, so the AV1 payloads it produced are taken out of
+/// its `mdat` box and wrapped again by avif-serialize with an Exif item. The
+/// container settings mirror what ravif writes for its default 10-bit YCbCr
+/// encoding.
+fn add_avif_exif(encoded: &ravif::EncodedImage, img: &DynamicImage, exif: &[u8]) -> Result<Vec<u8>> {
+    let mdat = iso_boxes(&encoded.avif_file)
+        .and_then(|boxes| boxes.into_iter().find(|(fourcc, _)| *fourcc == b"mdat"))
+        .map(|(_, payload)| payload);
+
+    // avif-serialize stores the alpha payload first, then the color payload
+    let (color_size, alpha_size) = (encoded.color_byte_size, encoded.alpha_byte_size);
+    let payloads = mdat
+        .filter(|mdat| mdat.len() == color_size + alpha_size)
+        .context("Unexpected AVIF layout from the encoder")?;
+    let (alpha, color) = payloads.split_at(alpha_size);
+
+    Ok(avif_serialize::Aviffy::new()
+        .matrix_coefficients(avif_serialize::constants::MatrixCoefficients::Bt601)
+        .set_exif(exif.to_vec())
+        .to_vec(color, (alpha_size > 0).then_some(alpha), img.width(), img.height(), 10))
+}
+
+/// avif-serialize can't attach XMP either, so the packet is appended to `mdat` as
+/// a `mime` item: `meta` gets an `infe` entry, an `iloc` extent and a `cdsc`
+/// reference to the primary image for it, and every existing `iloc` offset moves
+/// by however much `meta` grew. Only avif-serialize's own layout is handled:
+/// `ftyp`, `meta` and `mdat` with 32-bit sizes, and a version 0 `iloc` with
+/// 4-byte absolute offsets and lengths.
+fn add_avif_xmp(file: &[u8], xmp: &[u8]) -> Result<Vec<u8>> {
+    const CONTENT_TYPE: &[u8] = b"application/rdf+xml\0";
+    /// Item ID, data reference index, extent count, then one offset and length
+    const ILOC_ITEM_SIZE: usize = 2 + 2 + 2 + 4 + 4;
+
+    let layout = || anyhow!("Unexpected AVIF layout from the encoder");
+    let top = iso_boxes(file).ok_or_else(layout)?;
+    let [(b"ftyp", ftyp), (b"meta", meta), (b"mdat", mdat)] = top.as_slice() else {
+        return Err(layout());
+    };
+    let children = meta.get(4..).and_then(iso_boxes).ok_or_else(layout)?;
+    let child = |name: &[u8; 4]| children.iter().find(|(fourcc, _)| *fourcc == name).map(|(_, payload)| *payload);
+
+    let primary = child(b"pitm").and_then(|pitm| pitm.get(4..6)).ok_or_else(layout)?;
+    let iloc = child(b"iloc").ok_or_else(layout)?;
+    let items = avif_iloc_items(iloc).ok_or_else(layout)?;
+    let item_id = items.iter().map(|(id, _)| *id).max().unwrap_or(0) + 1;
+
+    let mut infe = vec![2, 0, 0, 0];
+    infe.extend(item_id.to_be_bytes());
+    infe.extend([0, 0]); // item_protection_index
+    infe.extend(b"mime");
+    infe.push(0); // empty item_name
+    infe.extend(CONTENT_TYPE);
+
+    let mut cdsc = item_id.to_be_bytes().to_vec();
+    cdsc.extend(1u16.to_be_bytes());
+    cdsc.extend(primary);
+    let cdsc = iso_box(b"cdsc", &cdsc);
+
+    // Every box but `iloc`, whose offsets depend on the final size of `meta`
+    let mut rewritten: Vec<(&[u8; 4], Vec<u8>)> = Vec::new();
+    for &(fourcc, payload) in &children {
+        let payload = match fourcc {
+            b"iinf" if payload.starts_with(&[0, 0, 0, 0]) && payload.len() >= 6 => {
+                let count = u16::from_be_bytes([payload[4], payload[5]]) + 1;
+                [&payload[..4], &count.to_be_bytes(), &payload[6..], &iso_box(b"infe", &infe)].concat()
+            }
+            b"iinf" => return Err(layout()),
+            b"iref" => [payload, &cdsc].concat(),
+            _ => payload.to_vec(),
+        };
+        rewritten.push((fourcc, payload));
+    }
+    if child(b"iref").is_none() {
+        // avif-serialize writes `iref`, when it has one, right after `iinf`
+        let at = rewritten.iter().position(|(fourcc, _)| *fourcc == b"iinf").ok_or_else(layout)? + 1;
+        rewritten.insert(at, (b"iref", [&[0, 0, 0, 0], cdsc.as_slice()].concat()));
+    }
+
+    let meta_size = 8 + 4 + rewritten.iter().map(|(_, payload)| 8 + payload.len()).sum::<usize>() + ILOC_ITEM_SIZE;
+    let shift = (meta_size - (8 + meta.len())) as u32;
+    let xmp_offset = u32::try_from(8 + ftyp.len() + meta_size + 8 + mdat.len()).context("AVIF file is too large")?;
+    let xmp_length = u32::try_from(xmp.len()).context("XMP packet is too large")?;
+
+    let mut new_iloc = iloc[..6].to_vec();
+    new_iloc.extend((items.len() as u16 + 1).to_be_bytes());
+    for (id, extents) in items.iter().chain([(item_id, vec![(xmp_offset - shift, xmp_length)])].iter()) {
+        new_iloc.extend(id.to_be_bytes());
+        new_iloc.extend([0, 0]); // data_reference_index: this file
+        new_iloc.extend((extents.len() as u16).to_be_bytes());
+        for (offset, length) in extents {
+            new_iloc.extend((offset + shift).to_be_bytes());
+            new_iloc.extend(length.to_be_bytes());
+        }
+    }
+    for (fourcc, payload) in &mut rewritten {
+        if *fourcc == b"iloc" {
+            *payload = std::mem::take(&mut new_iloc);
+        }
+    }
+
+    let mut meta = meta[..4].to_vec();
+    for (fourcc, payload) in &rewritten {
+        meta.extend(iso_box(fourcc, payload));
+    }
+    Ok([
+        iso_box(b"ftyp", ftyp),
+        iso_box(b"meta", &meta),
+        iso_box(b"mdat", &[mdat, xmp].concat()),
+    ]
+    .concat())
+}
+
+/// Splits ISO-BMFF data into its boxes' fourccs and payloads. Returns `None` for
+/// 64-bit or to-the-end sizes and boxes running past the data.
+fn iso_boxes(data: &[u8]) -> Option<Vec<(&[u8; 4], &[u8])>> {
+    let mut boxes = Vec::new();
+    let mut offset = 0;
+    while offset < data.len() {
+        let header = data.get(offset..offset + 8)?;
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if size < 8 {
+            return None;
+        }
+        let fourcc = header[4..].try_into().ok()?;
+        boxes.push((fourcc, data.get(offset + 8..offset + size)?));
+        offset += size;
+    }
+    Some(boxes)
+}
+
+fn iso_box(fourcc: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut data = ((8 + payload.len()) as u32).to_be_bytes().to_vec();
+    data.extend(fourcc);
+    data.extend(payload);
+    data
+}
+
+/// An item ID with its extents' absolute offsets and lengths.
+type IlocItem = (u16, Vec<(u32, u32)>);
+
+/// The items of an `iloc` payload as avif-serialize writes it.
+fn avif_iloc_items(iloc: &[u8]) -> Option<Vec<IlocItem>> {
+    // Version 0, 4-byte offsets and lengths, no base offsets
+    if iloc.get(..6)? != [0, 0, 0, 0, 0x44, 0] {
+        return None;
+    }
+    let mut data = iloc.get(6..)?;
+    let mut take = |length: usize| -> Option<&[u8]> {
+        let (head, rest) = data.split_at_checked(length)?;
+        data = rest;
+        Some(head)
+    };
+    let u16_at = |bytes: &[u8]| u16::from_be_bytes([bytes[0], bytes[1]]);
+    let u32_at = |bytes: &[u8]| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+
+    let count = u16_at(take(2)?);
+    let mut items = Vec::new();
+    for _ in 0..count {
+        let header = take(6)?;
+        // Data in other files isn't something an encoder writes
+        if u16_at(&header[2..]) != 0 {
+            return None;
+        }
+        let extents = (0..u16_at(&header[4..]))
+            .map(|_| take(8).map(|extent| (u32_at(extent), u32_at(&extent[4..]))))
+            .collect::<Option<_>>()?;
+        items.push((u16_at(header), extents));
+    }
+    Some(items)
+}
+
+/// Progressive JPEG with optimized Huffman tables. JPEG has no alpha channel, so
+/// transparent pixels are flattened onto the variant background, or white if the
+/// background is itself transparent.
+/// Composites transparent pixels onto the variant's background, or white when
+/// it's transparent, the way JPEG output shows them.
+pub fn flatten_for_jpeg(img: &DynamicImage, variant: &Variant) -> DynamicImage {
+    if !img.color().has_alpha() {
+        return DynamicImage::ImageRgb8(img.to_rgb8());
+    }
+    let background = match variant.background.0 {
+        [_, _, _, 0] => Rgba([255, 255, 255, 255]),
+        [r, g, b, _] => Rgba([r, g, b, 255]),
+    };
+    let mut canvas = RgbaImage::from_pixel(img.width(), img.height(), background);
+    imageops::overlay(&mut canvas, &img.to_rgba8(), 0, 0);
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+}
+
+fn encode_jpeg(img: &DynamicImage, variant: &Variant, icc_profile: Option<&[u8]>, metadata: &Metadata) -> Result<Vec<u8>> {
+    let pixels = flatten_for_jpeg(img, variant).into_rgb8();
+
+    let width = u16::try_from(img.width()).context("Image is too wide for JPEG")?;
+    let height = u16::try_from(img.height()).context("Image is too tall for JPEG")?;
+
+    let mut buffer = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut buffer, variant.quality.unwrap_or(JPEG_DEFAULT_QUALITY));
+    encoder.set_progressive(true);
+    encoder.set_optimized_huffman_tables(true);
+    if let Some(icc_profile) = icc_profile {
+        encoder.add_icc_profile(icc_profile).context("Failed to embed ICC profile in JPEG")?;
+    }
+    if let Some(exif) = &metadata.exif {
+        add_jpeg_app1(&mut encoder, b"Exif\0\0", exif.as_slice(), "EXIF");
+    }
+    if let Some(xmp) = &metadata.xmp {
+        add_jpeg_app1(&mut encoder, b"http://ns.adobe.com/xap/1.0/\0", xmp.as_bytes(), "XMP");
+    }
+    encoder.encode(&pixels, width, height, jpeg_encoder::ColorType::Rgb)
+        .context("Failed to encode image as JPEG")?;
+
+    Ok(buffer)
+}
+
+/// APP1 segments hold at most 64 KB. Larger blocks are left out rather than
+/// failing the variant.
+fn add_jpeg_app1(encoder: &mut jpeg_encoder::Encoder<&mut Vec<u8>>, header: &[u8], data: &[u8], kind: &str) {
+    let segment = [header, data].concat();
+    if let Err(e) = encoder.add_app_segment(1, &segment) {
+        tracing::warn!("Leaving {} metadata ({} bytes) out of JPEG: {}", kind, data.len(), e);
+    }
+}
+
+fn encode_png(img: &DynamicImage) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    img.write_to(&mut Cursor::new(&mut buffer), ImageFormat::Png)
+        .context("Failed to encode image as PNG")?;
+
+    oxipng::optimize_from_memory(&buffer, &oxipng::Options::from_preset(PNG_OPTIMIZATION_LEVEL))
+        .context("Failed to optimize PNG")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::experimental::Writer;
+    use exif::{Field, In, Tag, Value};
+    use image::codecs::webp::WebPDecoder;
+    use image::ImageDecoder;
+
+    const XMP: &str = concat!(
+        r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+        r#"<rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/">"#,
+        r#"<dc:rights><rdf:Alt><rdf:li xml:lang="x-default">Jane Doe</rdf:li></rdf:Alt></dc:rights>"#,
+        r#"</rdf:Description></rdf:RDF></x:xmpmeta>"#,
+    );
+
+    fn variant(format: &str) -> Variant {
+        toml::from_str(&format!("name = \"test\"\nformat = \"{}\"", format)).unwrap()
+    }
+
+    fn image(alpha: bool) -> DynamicImage {
+        let img = RgbaImage::from_fn(64, 48, |x, y| Rgba([(x * 4) as u8, (y * 5) as u8, 128, if alpha { (x * 4) as u8 } else { 255 }]));
+        match alpha {
+            true => DynamicImage::ImageRgba8(img),
+            false => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(img).to_rgb8()),
+        }
+    }
+
+    fn metadata() -> Metadata {
+        let copyright = Field {
+            tag: Tag::Copyright,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![b"Jane Doe".to_vec()]),
+        };
+        let mut writer = Writer::new();
+        writer.push_field(&copyright);
+        let mut exif = Cursor::new(Vec::new());
+        writer.write(&mut exif, false).unwrap();
+        Metadata {
+            exif: Some(exif.into_inner()),
+            xmp: Some(XMP.to_string()),
+        }
+    }
+
+    /// Reads the EXIF copyright back the way kamadak-exif finds it in each container.
+    fn copyright(file: &[u8]) -> String {
+        let exif = exif::Reader::new().read_from_container(&mut Cursor::new(file)).unwrap();
+        let field = exif.get_field(Tag::Copyright, In::PRIMARY).unwrap();
+        field.display_value().to_string()
+    }
+
+    fn webp_chunks<'a>(webp: &'a [u8], name: &[u8; 4]) -> Vec<&'a [u8]> {
+        let mut chunks = Vec::new();
+        let mut offset = 12;
+        while offset + 8 <= webp.len() {
+            let size = u32::from_le_bytes(webp[offset + 4..offset + 8].try_into().unwrap()) as usize;
+            if &webp[offset..offset + 4] == name {
+                chunks.push(&webp[offset + 8..offset + 8 + size]);
+            }
+            offset += 8 + size + (size & 1);
+        }
+        chunks
+    }
+
+    fn webp_chunk<'a>(webp: &'a [u8], name: &[u8; 4]) -> Option<&'a [u8]> {
+        webp_chunks(webp, name).into_iter().next()
+    }
+
+    /// Data of the first item of `item_type`, located through `iinf` and `iloc`.
+    fn avif_item(file: &[u8], item_type: &[u8; 4]) -> Option<Vec<u8>> {
+        let top = iso_boxes(file)?;
+        let (_, meta) = top.iter().find(|(fourcc, _)| *fourcc == b"meta")?;
+        let children = iso_boxes(&meta[4..])?;
+        let child = |name: &[u8; 4]| children.iter().find(|(fourcc, _)| *fourcc == name).map(|(_, payload)| *payload);
+
+        let id = iso_boxes(&child(b"iinf")?[6..])?
+            .into_iter()
+            .map(|(_, infe)| (u16::from_be_bytes([infe[4], infe[5]]), &infe[8..12]))
+            .find(|(_, typ)| *typ == item_type)?
+            .0;
+        let (_, extents) = avif_iloc_items(child(b"iloc")?)?.into_iter().find(|(item, _)| *item == id)?;
+        Some(
+            extents
+                .iter()
+                .flat_map(|&(offset, length)| &file[offset as usize..(offset + length) as usize])
+                .copied()
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn webp_round_trips_profile_and_metadata() {
+        for alpha in [false, true] {
+            let webp = encode(&image(alpha), &variant("webp"), ColorProfile::DisplayP3, &metadata()).unwrap();
+
+            let mut decoder = WebPDecoder::new(Cursor::new(&webp)).unwrap();
+            assert_eq!(decoder.icc_profile().as_deref(), color::icc_profile(ColorProfile::DisplayP3));
+            let decoded = DynamicImage::from_decoder(decoder).unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (64, 48));
+            assert_eq!(decoded.color().has_alpha(), alpha);
+
+            assert_eq!(copyright(&webp), "\"Jane Doe\"");
+            assert_eq!(webp_chunk(&webp, b"XMP "), Some(XMP.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn avif_round_trips_metadata() {
+        for alpha in [false, true] {
+            for metadata in [Metadata { xmp: None, ..metadata() }, Metadata { exif: None, ..metadata() }, metadata()] {
+                let plain = encode(&image(alpha), &variant("avif"), ColorProfile::Srgb, &Metadata::default()).unwrap();
+                let avif = encode(&image(alpha), &variant("avif"), ColorProfile::Srgb, &metadata).unwrap();
+
+                // The AV1 payloads are still where `iloc` says after every rewrite
+                assert_eq!(avif_item(&avif, b"av01"), avif_item(&plain, b"av01"));
+                assert!(avif_item(&avif, b"av01").is_some());
+                if metadata.exif.is_some() {
+                    assert_eq!(copyright(&avif), "\"Jane Doe\"");
+                }
+                let xmp = metadata.xmp.as_ref().map(|xmp| xmp.as_bytes().to_vec());
+                assert_eq!(avif_item(&avif, b"mime"), xmp);
+            }
+        }
+    }
+
+    #[test]
+    fn animated_webp_keeps_every_frame_delay() {
+        let frames: Vec<Frame> = [100, 200, 300]
+            .into_iter()
+            .enumerate()
+            .map(|(i, delay_ms)| Frame {
+                image: RgbaImage::from_pixel(16, 16, Rgba([(i * 100) as u8, 0, 0, 255])),
+                delay_ms,
+            })
+            .collect();
+        let webp = encode_animated_webp(&frames, 0, &variant("webp")).unwrap();
+
+        // ANMF payload: X, Y, width - 1, height - 1, then the 24-bit duration
+        let durations: Vec<u32> = webp_chunks(&webp, b"ANMF")
+            .into_iter()
+            .map(|anmf| u32::from_le_bytes([anmf[12], anmf[13], anmf[14], 0]))
+            .collect();
+        assert_eq!(durations, [100, 200, 300]);
+        assert_eq!(durations.iter().sum::<u32>(), 600);
+    }
+}
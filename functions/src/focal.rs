@@ -0,0 +1,120 @@
+use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+/// Where the subject of an image is, as set by the uploader through
+/// `x-amz-meta-focal-x`/`x-amz-meta-focal-y` or `x-amz-meta-focal-box`
+/// (`x,y,width,height`). Values are fractions of the image size from 0.0 to 1.0,
+/// or pixels when greater than 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FocalHint {
+    Point { x: f64, y: f64 },
+    Region { x: f64, y: f64, width: f64, height: f64 },
+}
+
+impl FocalHint {
+    /// Reads the hint from S3 user metadata, whose keys arrive without the
+    /// `x-amz-meta-` prefix. A box takes precedence over a point.
+    pub fn from_metadata(metadata: &HashMap<String, String>) -> Result<Option<Self>> {
+        if let Some(value) = metadata.get("focal-box") {
+            let parts = value
+                .split(',')
+                .map(parse_coordinate)
+                .collect::<Result<Vec<_>>>()?;
+            let [x, y, width, height] = parts[..] else {
+                bail!("focal-box must be x,y,width,height, got '{}'", value);
+            };
+            return Ok(Some(FocalHint::Region { x, y, width, height }));
+        }
+
+        match (metadata.get("focal-x"), metadata.get("focal-y")) {
+            (Some(x), Some(y)) => Ok(Some(FocalHint::Point {
+                x: parse_coordinate(x)?,
+                y: parse_coordinate(y)?,
+            })),
+            (None, None) => Ok(None),
+            _ => bail!("focal-x and focal-y must be set together"),
+        }
+    }
+
+    /// The point to keep in frame, in pixels of an image with the given dimensions.
+    pub fn center(&self, dimensions: (u32, u32)) -> (f64, f64) {
+        let (width, height) = (dimensions.0 as f64, dimensions.1 as f64);
+        match *self {
+            FocalHint::Point { x, y } => (to_pixels(x, width), to_pixels(y, height)),
+            FocalHint::Region { x, y, width: w, height: h } => (
+                to_pixels(x, width) + to_pixels(w, width) / 2.0,
+                to_pixels(y, height) + to_pixels(h, height) / 2.0,
+            ),
+        }
+    }
+
+    /// The same hint with pixel values converted to fractions of an image of
+    /// `size`, for images rendered at a different size than the one the hint's
+    /// pixels refer to. Fractions stay as they are.
+    pub fn relative_to(self, size: (f64, f64)) -> Self {
+        let (width, height) = size;
+        let fraction = |value: f64, size: f64| {
+            if value <= 1.0 {
+                value
+            } else {
+                (value / size).min(1.0)
+            }
+        };
+        match self {
+            FocalHint::Point { x, y } => FocalHint::Point {
+                x: fraction(x, width),
+                y: fraction(y, height),
+            },
+            FocalHint::Region { x, y, width: w, height: h } => FocalHint::Region {
+                x: fraction(x, width),
+                y: fraction(y, height),
+                width: fraction(w, width),
+                height: fraction(h, height),
+            },
+        }
+    }
+
+    /// Top-left corner of a `crop_w` x `crop_h` window centered on the hint and
+    /// kept inside the image.
+    pub fn crop_origin(&self, dimensions: (u32, u32), crop_w: u32, crop_h: u32) -> (u32, u32) {
+        let (center_x, center_y) = self.center(dimensions);
+        let place = |center: f64, window: u32, size: u32| {
+            let max = size.saturating_sub(window) as f64;
+            (center - window as f64 / 2.0).clamp(0.0, max).round() as u32
+        };
+
+        (
+            place(center_x, crop_w, dimensions.0),
+            place(center_y, crop_h, dimensions.1),
+        )
+    }
+}
+
+fn parse_coordinate(value: &str) -> Result<f64> {
+    match value.trim().parse::<f64>() {
+        Ok(number) if number.is_finite() && number >= 0.0 => Ok(number),
+        _ => bail!("Invalid focal coordinate '{}'", value),
+    }
+}
+
+fn to_pixels(value: f64, size: f64) -> f64 {
+    if value <= 1.0 {
+        value * size
+    } else {
+        value.min(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixel_hints_follow_the_rendered_size() {
+        // A hint at (300, 50) in a 400x100 SVG rendered at 4x
+        let hint = FocalHint::Region { x: 280.0, y: 40.0, width: 40.0, height: 20.0 }.relative_to((400.0, 100.0));
+        assert_eq!(hint.center((1600, 400)), (1200.0, 200.0));
+        let point = FocalHint::Point { x: 0.25, y: 100.0 }.relative_to((400.0, 100.0));
+        assert_eq!(point, FocalHint::Point { x: 0.25, y: 1.0 });
+    }
+}
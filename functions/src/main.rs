@@ -1,12 +1,50 @@
 use anyhow::{Context, Result};
 use aws_config::BehaviorVersion;
 use aws_sdk_s3::{Client as S3Client, primitives::ByteStream};
-use image::{ImageFormat, DynamicImage};
+use aws_sdk_s3::types::{MetadataDirective, TaggingDirective};
+use image::{DynamicImage, GenericImageView, Rgba};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
-use std::io::Cursor;
-
-const TARGET_WIDTHS: &[u32] = &[240, 480, 768, 1200, 1920];
+use std::borrow::Cow;
+use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+mod animation;
+mod color;
+mod config;
+mod decode;
+mod duplicates;
+mod encode;
+mod focal;
+mod metadata;
+mod orientation;
+mod palette;
+mod phash;
+mod placeholder;
+mod quality;
+#[cfg(feature = "raw")]
+mod raw;
+mod resample;
+mod resize;
+mod sharpen;
+mod smartcrop;
+mod ssim;
+mod svg;
+
+use animation::Animation;
+use color::ColorProfile;
+use config::{Config, Naming, OutputFormat, UpscalePolicy, Variant};
+use duplicates::{DuplicatePolicy, IndexedSource};
+use focal::FocalHint;
+use metadata::{Metadata, MetadataPolicy, SourceMetadata};
+use orientation::Orientation;
+use palette::Palette;
+use phash::PerceptualHash;
+use placeholder::Placeholders;
+use quality::Budget;
+use resize::{Fit, ResizeSpec};
+use svg::VectorImage;
 
 #[derive(Deserialize)]
 struct EventBridgeEvent {
@@ -29,12 +67,90 @@ struct ObjectInfo {
     key: String,
 }
 
+/// User metadata set on every generated object, holding the URL-encoded source key.
+/// Objects carrying it are never processed again when their own upload event arrives.
+const GENERATED_FROM_METADATA: &str = "generated-from";
+/// Object tag set on every generated object. The bucket policy makes tagged objects
+/// public, which lets JPEG and PNG fallbacks be served while the originals stay private.
+const GENERATED_TAG: &str = "generated-by=image-downscaler";
+/// User metadata holding the encoder quality of lossy variants, which `max_bytes`
+/// may have lowered from the configured one.
+const QUALITY_METADATA: &str = "quality";
+/// User metadata holding the dimensions and hex SHA-256 of every variant, so the
+/// manifest can list variants that already existed without downloading them.
+const WIDTH_METADATA: &str = "width";
+const HEIGHT_METADATA: &str = "height";
+const SHA256_METADATA: &str = "sha256";
+/// User metadata holding the ETag of the source a variant was generated from. A
+/// variant that already exists is only kept when it matches the current source.
+const SOURCE_ETAG_METADATA: &str = "source-etag";
+/// Name of the JSON sidecar listing every variant, e.g. `photo.manifest.json`.
+const MANIFEST_SIDECAR: &str = "manifest";
+/// User metadata on the main WebP holding the source's BlurHash and ThumbHash.
+const BLURHASH_METADATA: &str = "blurhash";
+const THUMBHASH_METADATA: &str = "thumbhash";
+/// User metadata on every variant holding the source's dominant color as `#rrggbb`.
+const DOMINANT_COLOR_METADATA: &str = "dominant-color";
+/// Name of the JSON sidecar holding the placeholders, e.g. `photo.placeholders.json`.
+const PLACEHOLDERS_SIDECAR: &str = "placeholders";
+/// Hex digits of the source's SHA-256 in fingerprinted variant keys.
+const FINGERPRINT_LENGTH: usize = 6;
+/// Variants with `max_bytes` give up shrinking to fit once they are this narrow.
+const MIN_BUDGET_WIDTH: u32 = 16;
+
+struct SourceObject {
+    body: Vec<u8>,
+    /// ETag without its surrounding quotes.
+    etag: Option<String>,
+    /// User metadata, keyed without the `x-amz-meta-` prefix.
+    metadata: HashMap<String, String>,
+}
+
 #[derive(Serialize)]
 struct Response {
     message: String,
+    variants: Vec<GeneratedVariant>,
+}
+
+/// A variant that exists in the bucket after processing, with its actual dimensions.
+#[derive(Debug, Serialize)]
+struct GeneratedVariant {
+    name: String,
+    key: String,
+    format: OutputFormat,
+    width: u32,
+    height: u32,
+    /// Size of the object in bytes.
+    bytes: u64,
+    /// Hex SHA-256 of the object body.
+    sha256: String,
+    content_type: &'static str,
 }
 
-async fn function_handler(event: LambdaEvent<EventBridgeEvent>) -> Result<Response, Error> {
+/// Every variant of a source, written to `photo.manifest.json` so static site
+/// generators can build `srcset` attributes from the widths that actually exist.
+#[derive(Serialize)]
+struct Manifest<'a> {
+    source: &'a str,
+    /// ETag of the source object the variants were generated from.
+    source_etag: Option<&'a str>,
+    /// `dominant_color` and `palette`, left out for fully transparent sources.
+    #[serde(flatten)]
+    palette: Option<&'a Palette>,
+    /// Content fingerprint in the variant keys, with `naming = "fingerprinted"`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fingerprint: Option<&'a str>,
+    /// An earlier upload under the same prefix this source is a near-duplicate of.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duplicate_of: Option<&'a str>,
+    variants: &'a [GeneratedVariant],
+}
+
+async fn function_handler(
+    s3_client: &S3Client,
+    config: &Config,
+    event: LambdaEvent<EventBridgeEvent>,
+) -> Result<Response, Error> {
     let bucket_name = &event.payload.detail.bucket.name;
     let key = decode_key(&event.payload.detail.object.key);
 
@@ -42,91 +158,329 @@ async fn function_handler(event: LambdaEvent<EventBridgeEvent>) -> Result<Respon
         tracing::warn!("Unsupported event payload");
         return Ok(Response {
             message: "Unsupported event payload".to_string(),
+            variants: Vec::new(),
         });
     }
 
-    let config = aws_config::load_defaults(BehaviorVersion::latest()).await;
-    let s3_client = S3Client::new(&config);
-
-    if let Err(e) = handle_key(&s3_client, bucket_name, &key).await {
-        tracing::error!("Failed to handle key {}: {}", key, e);
-        return Err(e.into());
-    }
+    let variants = match handle_key(s3_client, config, bucket_name, &key).await {
+        Ok(variants) => variants,
+        Err(e) => {
+            tracing::error!("Failed to handle key {}: {}", key, e);
+            return Err(e.into());
+        }
+    };
 
     Ok(Response {
         message: "Successfully processed image".to_string(),
+        variants,
     })
 }
 
-async fn handle_key(s3_client: &S3Client, bucket_name: &str, key: &str) -> Result<()> {
+async fn handle_key(
+    s3_client: &S3Client,
+    config: &Config,
+    bucket_name: &str,
+    key: &str,
+) -> Result<Vec<GeneratedVariant>> {
+    // Every object this function writes fires the upload rule again, so generated
+    // ones are recognized from their metadata before paying for a full download
+    match is_generated(s3_client, bucket_name, key).await {
+        Ok(true) => {
+            tracing::info!("Skipping generated object {}", key);
+            return Ok(Vec::new());
+        }
+        Ok(false) => {}
+        Err(e) => {
+            tracing::error!("Failed to read source object {}: {}", key, e);
+            return Ok(Vec::new());
+        }
+    }
+
     // Download the original image
-    let body = match download_object(s3_client, bucket_name, key).await {
-        Ok(body) => body,
+    let source = match download_object(s3_client, bucket_name, key).await {
+        Ok(source) => source,
         Err(e) => {
             tracing::error!("Failed to read source object {}: {}", key, e);
-            return Ok(()); // Don't fail the Lambda, just skip this object
+            return Ok(Vec::new()); // Don't fail the Lambda, just skip this object
         }
     };
 
     // Try to load as image to validate it's an image file
-    let img = match image::load_from_memory(&body) {
-        Ok(img) => img,
+    let mut decoded = match decode::decode(&source.body) {
+        Ok(decoded) => decoded,
         Err(e) => {
             tracing::warn!("Skipping non-image object {}: {}", key, e);
-            return Ok(());
+            return Ok(Vec::new());
         }
     };
 
-    let webp_key = to_webp_key(key);
-    if webp_key.is_empty() {
-        return Ok(());
+    let source_sha256 = sha256_hex(&source.body);
+    let fingerprint = match config.naming {
+        Naming::Plain => None,
+        Naming::Fingerprinted => Some(source_sha256[..FINGERPRINT_LENGTH].to_string()),
+    };
+
+    // Turn phone photos upright before anything is measured or cropped, since the
+    // orientation tag doesn't survive encoding. libheif already applies HEIF's own
+    // rotation, which the EXIF tag duplicates.
+    if !decode::is_heif(&source.body) {
+        match Orientation::from_exif(&source.body) {
+            Ok(Some(orientation)) => decoded.image = orientation.apply(&decoded.image),
+            Ok(None) => {}
+            Err(e) => tracing::warn!("Ignoring EXIF orientation on {}: {}", key, e),
+        }
     }
 
-    // Create main WebP version if it doesn't exist
-    if !object_exists(s3_client, bucket_name, &webp_key).await? {
-        let webp_body = convert_to_webp(&img, None)?;
-        put_webp_object(s3_client, bucket_name, &webp_key, webp_body).await?;
+    // Bring sources with an embedded profile into the configured color space
+    if let Some(icc_profile) = decoded.icc_profile.take() {
+        match color::convert(&decoded.image, &icc_profile, config.color_profile) {
+            Ok((image, color)) => {
+                decoded.image = image;
+                decoded.color = color;
+            }
+            Err(e) => tracing::warn!("Ignoring ICC profile on {}: {}", key, e),
+        }
     }
 
-    // Create sized versions
-    let max_width = 1920;
-    let width_targets: Vec<u32> = TARGET_WIDTHS.iter()
-        .filter(|&&width| width <= max_width)
-        .copied()
-        .collect();
+    let placeholders = match Placeholders::compute(&decoded.image, decoded.color) {
+        Ok(placeholders) => Some(placeholders),
+        Err(e) => {
+            tracing::warn!("No placeholders for {}: {}", key, e);
+            None
+        }
+    };
 
-    if width_targets.is_empty() {
-        return Ok(());
-    }
+    let palette = Palette::extract(&decoded.image, decoded.color).unwrap_or_else(|e| {
+        tracing::warn!("No palette for {}: {}", key, e);
+        None
+    });
+
+    let decoded = Arc::new(decoded);
+
+    // Only read what some variant may carry over
+    let source_metadata = if config.variants.iter().all(|v| v.metadata == MetadataPolicy::Strip) {
+        SourceMetadata::default()
+    } else {
+        SourceMetadata::read(&source.body)
+    };
+    let source_metadata = Arc::new(source_metadata);
+    let img = &decoded.image;
 
-    // Process all sizes concurrently
-    let tasks: Vec<_> = width_targets.into_iter().map(|width| {
+    let focal = match FocalHint::from_metadata(&source.metadata) {
+        Ok(focal) => focal,
+        Err(e) => {
+            tracing::warn!("Ignoring focal hint on {}: {}", key, e);
+            None
+        }
+    };
+
+    // Animations and SVGs aren't indexed: sharing a frame with a photo doesn't make
+    // their variants interchangeable
+    let duplicate = match config.duplicates {
+        DuplicatePolicy::Off => None,
+        _ if decoded.animation.is_some() || decoded.vector.is_some() => None,
+        policy => {
+            let indexed = IndexedSource {
+                key: key.to_string(),
+                hash: PerceptualHash::compute(img),
+                sha256: source_sha256,
+                focal_hint: focal.is_some(),
+                fingerprint: fingerprint.clone(),
+            };
+            match duplicates::register(s3_client, bucket_name, &indexed).await {
+                Ok(Some(duplicate)) => {
+                    let reuse = policy == DuplicatePolicy::Reuse && duplicate.can_reuse_for(&indexed);
+                    tracing::warn!(
+                        "{} is a near-duplicate of {}{}",
+                        key,
+                        duplicate.key,
+                        if reuse { ", reusing its variants" } else { "" }
+                    );
+                    Some((duplicate, reuse))
+                }
+                Ok(None) => None,
+                Err(e) => {
+                    tracing::warn!("Not checking {} for duplicates: {}", key, e);
+                    None
+                }
+            }
+        }
+    };
+    let reuse_from = match &duplicate {
+        Some((duplicate, true)) => Some(duplicate.clone()),
+        _ => None,
+    };
+
+    // Apply the source filters and upscale policy before any work is scheduled
+    let transparent = has_transparency(img);
+    let variants: Vec<Variant> = config.variants.iter().filter_map(|variant| {
+        if !variant.sources.includes(transparent) {
+            return None;
+        }
+        // Only WebP variants of an animation are animated, and a still AVIF would
+        // be served in place of the GIF
+        if decoded.animation.is_some() && variant.format == OutputFormat::Avif {
+            tracing::info!("Skipping variant {} for animated source {}", variant.name, key);
+            return None;
+        }
+        let Some(requested) = variant.resize_spec() else {
+            return Some(variant.clone());
+        };
+
+        // Vectors render crisply at any size, so they are never "upscaled"
+        let policy = match decoded.vector {
+            Some(_) => UpscalePolicy::Allow,
+            None => config.upscale_policy(variant),
+        };
+
+        match policy.apply(requested, img.dimensions()) {
+            Some(spec) => Some(Variant { width: Some(spec.width), height: spec.height, ..variant.clone() }),
+            None => {
+                tracing::info!(
+                    "Skipping variant {} ({}x{}) for {}x{} source {}",
+                    variant.name,
+                    requested.width,
+                    requested.height.map_or_else(|| "auto".to_string(), |h| h.to_string()),
+                    img.width(),
+                    img.height(),
+                    key
+                );
+                None
+            }
+        }
+    }).collect();
+
+    // Process all variants concurrently
+    let tasks: Vec<_> = variants.into_iter().map(|variant| {
         let s3_client = s3_client.clone();
         let bucket_name = bucket_name.to_string();
         let key = key.to_string();
-        let img = img.clone();
+        let decoded = decoded.clone();
+        let source_metadata = source_metadata.clone();
+        let fingerprint = fingerprint.clone();
+        // Variants carrying source metadata must not get the duplicate's
+        let reuse_from = reuse_from.clone().filter(|_| variant.metadata == MetadataPolicy::Strip);
+        // The main WebP carries the placeholders, so a HEAD request is enough to read them
+        let mut user_metadata = match &placeholders {
+            Some(placeholders) if is_main_variant(&variant) => vec![
+                (BLURHASH_METADATA, placeholders.blurhash.clone()),
+                (THUMBHASH_METADATA, placeholders.thumbhash.clone()),
+            ],
+            _ => Vec::new(),
+        };
+        if let Some(palette) = &palette {
+            user_metadata.push((DOMINANT_COLOR_METADATA, palette.dominant_color.clone()));
+        }
+        let source_etag = source.etag.clone();
+        if let Some(etag) = &source_etag {
+            user_metadata.push((SOURCE_ETAG_METADATA, etag.clone()));
+        }
 
         tokio::spawn(async move {
-            let sized_key = to_sized_webp_key(&key, width);
+            let img = &decoded.image;
+            let variant_key = to_variant_key(&key, fingerprint.as_deref(), &variant);
+            if variant_key == key {
+                anyhow::bail!("Variant {} would overwrite its source {}", variant.name, key);
+            }
+            let (width, height) = variant.resize_spec()
+                .map_or(img.dimensions(), |spec| spec.output_dimensions(img.dimensions()));
+            let generated = GeneratedVariant {
+                name: variant.name.clone(),
+                key: variant_key.clone(),
+                format: variant.format,
+                width,
+                height,
+                bytes: 0,
+                sha256: String::new(),
+                content_type: variant.format.content_type(),
+            };
+
+            if let Some(stored) = stored_object(&s3_client, &bucket_name, &variant_key).await? {
+                // Variant keys share the bucket with uploads, e.g. an editor's own
+                // `banner-1200.jpg`, which must never be overwritten or copied over
+                let generated_from = stored.metadata.get(GENERATED_FROM_METADATA).map(String::as_str);
+                if generated_from != Some(urlencoding::encode(&key).as_ref()) {
+                    anyhow::bail!("Not overwriting {}, which wasn't generated from {}", variant_key, key);
+                }
+                // A replaced source keeps its plain variant keys, which then hold stale pixels
+                if source_etag.is_none() || stored.metadata.get(SOURCE_ETAG_METADATA) == source_etag.as_ref() {
+                    return stored_variant(&s3_client, &bucket_name, &variant_key, stored, generated).await;
+                }
+                tracing::info!("Regenerating {} for the replaced source {}", variant_key, key);
+            }
 
-            if object_exists(&s3_client, &bucket_name, &sized_key).await? {
-                return Ok::<(), anyhow::Error>(());
+            if let Some(duplicate) = &reuse_from {
+                let from = to_variant_key(&duplicate.key, duplicate.fingerprint.as_deref(), &variant);
+                let copy = copy_variant_object(&s3_client, &bucket_name, &from, &variant_key, &key, variant.format, &user_metadata);
+                match copy.await {
+                    Ok(Some(stored)) => return stored_variant(&s3_client, &bucket_name, &from, stored, generated).await,
+                    Ok(None) => {}
+                    Err(e) => tracing::warn!("Encoding {} instead of copying {}: {}", variant_key, from, e),
+                }
             }
 
-            let resized_body = convert_to_webp(&img, Some(width))?;
-            put_webp_object(&s3_client, &bucket_name, &sized_key, resized_body).await?;
-            Ok(())
+            let metadata = source_metadata.filter(variant.metadata).unwrap_or_else(|e| {
+                tracing::warn!("Leaving metadata out of {}: {}", variant_key, e);
+                Metadata::default()
+            });
+
+            let encoded = match (&decoded.animation, &decoded.vector, variant.format) {
+                (Some(animation), _, OutputFormat::WebP) => convert_animation(animation, &variant, focal)?,
+                (_, Some(vector), _) => convert_vector(vector, &variant, focal, &metadata)?,
+                _ => convert_image(img, decoded.color, &variant, focal, &metadata)?,
+            };
+            let sha256 = sha256_hex(&encoded.body);
+            let generated = GeneratedVariant {
+                width: encoded.width,
+                height: encoded.height,
+                bytes: encoded.body.len() as u64,
+                sha256: sha256.clone(),
+                ..generated
+            };
+            let mut user_metadata = user_metadata;
+            user_metadata.push((SHA256_METADATA, sha256));
+            put_variant_object(&s3_client, &bucket_name, &variant_key, &key, variant.format, encoded, &user_metadata).await?;
+            Ok(generated)
         })
     }).collect();
 
     // Wait for all tasks to complete
+    let mut generated = Vec::new();
     for task in tasks {
-        if let Err(e) = task.await.context("Task join error")? {
-            tracing::error!("Failed to process sized image: {}", e);
+        match task.await.context("Task join error")? {
+            Ok(variant) => generated.push(variant),
+            Err(e) => tracing::error!("Failed to process image variant: {}", e),
         }
     }
 
-    Ok(())
+    if let Some(placeholders) = &placeholders {
+        let sidecar_key = to_sidecar_key(key, PLACEHOLDERS_SIDECAR);
+        let body = serde_json::to_vec(placeholders).context("Failed to serialize placeholders")?;
+        if let Err(e) = put_sidecar_object(s3_client, bucket_name, &sidecar_key, key, body).await {
+            tracing::error!("Failed to write placeholders for {}: {}", key, e);
+        }
+    }
+
+    let manifest = Manifest {
+        source: key,
+        source_etag: source.etag.as_deref(),
+        palette: palette.as_ref(),
+        fingerprint: fingerprint.as_deref(),
+        duplicate_of: duplicate.as_ref().map(|(duplicate, _)| duplicate.key.as_str()),
+        variants: &generated,
+    };
+    let body = serde_json::to_vec(&manifest).context("Failed to serialize manifest")?;
+    if let Err(e) = put_sidecar_object(s3_client, bucket_name, &to_sidecar_key(key, MANIFEST_SIDECAR), key, body).await {
+        tracing::error!("Failed to write manifest for {}: {}", key, e);
+    }
+
+    tracing::info!(
+        "Variants available for {}: {}",
+        key,
+        generated.iter().map(|v| format!("{} ({}x{})", v.key, v.width, v.height)).collect::<Vec<_>>().join(", ")
+    );
+
+    Ok(generated)
 }
 
 fn decode_key(key: &str) -> String {
@@ -141,31 +495,55 @@ fn decode_key(key: &str) -> String {
         .unwrap_or_else(|_| key.to_string())
 }
 
-fn to_webp_key(key: &str) -> String {
-    let last_slash = key.rfind('/').unwrap_or(0);
-    let last_dot = key.rfind('.');
-
-    match last_dot {
-        Some(dot_pos) if dot_pos > last_slash => {
-            format!("{}.webp", &key[..dot_pos])
-        }
-        _ => format!("{}.webp", key),
+/// Whether any pixel of `img` is not fully opaque. Images are often saved with an
+/// alpha channel they don't use, which doesn't count.
+fn has_transparency(img: &DynamicImage) -> bool {
+    match img {
+        _ if !img.color().has_alpha() => false,
+        DynamicImage::ImageRgba8(rgba) => rgba.pixels().any(|pixel| pixel[3] < u8::MAX),
+        _ => img.to_rgba16().pixels().any(|pixel| pixel[3] < u16::MAX),
     }
 }
 
-fn to_sized_webp_key(key: &str, width: u32) -> String {
+/// The source key without its extension.
+fn key_stem(key: &str) -> &str {
     let last_slash = key.rfind('/').unwrap_or(0);
     let last_dot = key.rfind('.');
 
     match last_dot {
-        Some(dot_pos) if dot_pos > last_slash => {
-            format!("{}-{}.webp", &key[..dot_pos], width)
-        }
-        _ => format!("{}-{}.webp", key, width),
+        Some(dot_pos) if dot_pos > last_slash => &key[..dot_pos],
+        _ => key,
     }
 }
 
-async fn object_exists(s3_client: &S3Client, bucket_name: &str, key: &str) -> Result<bool> {
+/// `photo.jpg` -> `photo-480.webp`, or `photo.3f9a1c-480.webp` with a fingerprint.
+fn to_variant_key(key: &str, fingerprint: Option<&str>, variant: &Variant) -> String {
+    match fingerprint {
+        Some(fingerprint) => format!("{}.{}{}.{}", key_stem(key), fingerprint, variant.suffix, variant.format.extension()),
+        None => format!("{}{}.{}", key_stem(key), variant.suffix, variant.format.extension()),
+    }
+}
+
+/// Key of a JSON sidecar next to the variants, e.g. `photo.jpg` -> `photo.placeholders.json`.
+fn to_sidecar_key(key: &str, name: &str) -> String {
+    format!("{}.{}.json", key_stem(key), name)
+}
+
+/// The WebP at the source's own dimensions, which the CDN serves for the source key.
+fn is_main_variant(variant: &Variant) -> bool {
+    variant.format == OutputFormat::WebP && variant.suffix.is_empty()
+}
+
+/// An object already in the bucket, as seen by [`stored_object`].
+struct StoredObject {
+    bytes: u64,
+    /// User metadata, keyed without the `x-amz-meta-` prefix.
+    metadata: HashMap<String, String>,
+}
+
+/// Looks up an existing object by fetching its first byte, which also returns its
+/// metadata and, in the `Content-Range` header, its full size.
+async fn stored_object(s3_client: &S3Client, bucket_name: &str, key: &str) -> Result<Option<StoredObject>> {
     match s3_client
         .get_object()
         .bucket(bucket_name)
@@ -174,11 +552,22 @@ async fn object_exists(s3_client: &S3Client, bucket_name: &str, key: &str) -> Re
         .send()
         .await
     {
-        Ok(_) => Ok(true),
+        Ok(response) => {
+            // "bytes 0-0/12345"
+            let bytes = response
+                .content_range()
+                .and_then(|range| range.rsplit('/').next())
+                .and_then(|total| total.parse().ok())
+                .unwrap_or_default();
+            Ok(Some(StoredObject {
+                bytes,
+                metadata: response.metadata().cloned().unwrap_or_default(),
+            }))
+        }
         Err(e) => {
             if let Some(service_error) = e.as_service_error() {
                 if service_error.is_no_such_key() {
-                    return Ok(false);
+                    return Ok(None);
                 }
             }
             Err(e.into())
@@ -186,7 +575,93 @@ async fn object_exists(s3_client: &S3Client, bucket_name: &str, key: &str) -> Re
     }
 }
 
-async fn download_object(s3_client: &S3Client, bucket_name: &str, key: &str) -> Result<Vec<u8>> {
+/// Describes a variant already in the bucket, filling in what its metadata
+/// records. `key` is the object to hash if it predates the `sha256` metadata.
+async fn stored_variant(
+    s3_client: &S3Client,
+    bucket_name: &str,
+    key: &str,
+    stored: StoredObject,
+    expected: GeneratedVariant,
+) -> Result<GeneratedVariant> {
+    let dimension = |name: &str, expected: u32| {
+        stored.metadata.get(name).and_then(|value| value.parse().ok()).unwrap_or(expected)
+    };
+    let sha256 = match stored.metadata.get(SHA256_METADATA) {
+        Some(sha256) => sha256.clone(),
+        None => sha256_hex(&download_object(s3_client, bucket_name, key).await?.body),
+    };
+
+    Ok(GeneratedVariant {
+        width: dimension(WIDTH_METADATA, expected.width),
+        height: dimension(HEIGHT_METADATA, expected.height),
+        bytes: stored.bytes,
+        sha256,
+        ..expected
+    })
+}
+
+/// Copies the variant of a duplicate source within the bucket instead of encoding
+/// it again, swapping in this source's metadata. Returns the copied object, or
+/// `None` if the duplicate doesn't have the variant.
+async fn copy_variant_object(
+    s3_client: &S3Client,
+    bucket_name: &str,
+    from: &str,
+    to: &str,
+    source_key: &str,
+    format: OutputFormat,
+    user_metadata: &[(&str, String)],
+) -> Result<Option<StoredObject>> {
+    let Some(stored) = stored_object(s3_client, bucket_name, from).await? else {
+        return Ok(None);
+    };
+
+    let mut metadata = stored.metadata.clone();
+    for name in [BLURHASH_METADATA, THUMBHASH_METADATA, DOMINANT_COLOR_METADATA] {
+        metadata.remove(name);
+    }
+    metadata.insert(GENERATED_FROM_METADATA.to_string(), urlencoding::encode(source_key).into_owned());
+    for (name, value) in user_metadata {
+        metadata.insert(name.to_string(), value.clone());
+    }
+
+    s3_client
+        .copy_object()
+        .bucket(bucket_name)
+        .key(to)
+        .copy_source(format!("{}/{}", bucket_name, urlencoding::encode(from)))
+        .metadata_directive(MetadataDirective::Replace)
+        .set_metadata(Some(metadata))
+        .content_type(format.content_type())
+        .cache_control("public, max-age=31536000, immutable")
+        .tagging_directive(TaggingDirective::Replace)
+        .tagging(GENERATED_TAG)
+        .send()
+        .await
+        .context("Failed to copy variant object")?;
+
+    Ok(Some(stored))
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Whether `key` is an object this function wrote, from a HeadObject request.
+async fn is_generated(s3_client: &S3Client, bucket_name: &str, key: &str) -> Result<bool> {
+    let response = s3_client
+        .head_object()
+        .bucket(bucket_name)
+        .key(key)
+        .send()
+        .await
+        .context("Failed to head object in S3")?;
+
+    Ok(response.metadata().is_some_and(|metadata| metadata.contains_key(GENERATED_FROM_METADATA)))
+}
+
+async fn download_object(s3_client: &S3Client, bucket_name: &str, key: &str) -> Result<SourceObject> {
     let response = s3_client
         .get_object()
         .bucket(bucket_name)
@@ -195,34 +670,179 @@ async fn download_object(s3_client: &S3Client, bucket_name: &str, key: &str) ->
         .await
         .context("Failed to get object from S3")?;
 
+    let metadata = response.metadata().cloned().unwrap_or_default();
+    let etag = response.e_tag().map(|etag| etag.trim_matches('"').to_string());
     let body = response.body.collect().await
         .context("Failed to read object body")?;
 
-    Ok(body.into_bytes().to_vec())
+    Ok(SourceObject {
+        body: body.into_bytes().to_vec(),
+        etag,
+        metadata,
+    })
+}
+
+/// An encoded variant with the dimensions and quality it ended up with.
+struct EncodedVariant {
+    body: Vec<u8>,
+    width: u32,
+    height: u32,
+    /// Encoder quality, for lossy variants.
+    quality: Option<u8>,
+}
+
+/// Resizes and encodes a still image whose pixels are in the `color` space. With
+/// `max_bytes`, the quality is lowered and then the box shrunk until it fits.
+fn convert_image(
+    img: &DynamicImage,
+    color: ColorProfile,
+    variant: &Variant,
+    focal: Option<FocalHint>,
+    metadata: &Metadata,
+) -> Result<EncodedVariant> {
+    let mut spec = variant.resize_spec();
+    loop {
+        let processed_img = match (&spec, &variant.sharpen) {
+            (Some(spec), Some(sharpen)) => {
+                sharpen.apply(&resize::apply(img, spec, Rgba(variant.background.0), variant.crop, focal))
+            }
+            (Some(spec), None) => resize::apply(img, spec, Rgba(variant.background.0), variant.crop, focal),
+            (None, _) => img.clone(),
+        };
+        let (processed_img, color) = if variant.format.embeds_icc_profile() {
+            (processed_img, color)
+        } else {
+            (color::to_srgb(&processed_img, color)?, ColorProfile::Srgb)
+        };
+        let (width, height) = processed_img.dimensions();
+
+        let encode_at = |quality| {
+            let variant = Variant { quality: Some(quality), ..variant.clone() };
+            encode::encode(&processed_img, &variant, color, metadata)
+        };
+        let auto_variant;
+        let variant = match variant.max_dssim {
+            Some(max_dssim) => {
+                let max_quality = encode::quality(variant).context("max_dssim needs a lossy format")?;
+                let decode = |bytes: &[u8]| decode::decode_output(bytes, variant.format);
+                // Compare against what the JPEG shows, not against transparency
+                let reference = match variant.format {
+                    OutputFormat::Jpeg => Cow::Owned(encode::flatten_for_jpeg(&processed_img, variant)),
+                    _ => Cow::Borrowed(&processed_img),
+                };
+                let quality = quality::auto_quality(&reference, max_dssim, max_quality, encode_at, decode)?;
+                auto_variant = Variant { quality: Some(quality), ..variant.clone() };
+                &auto_variant
+            }
+            None => variant,
+        };
+
+        let Some(max_bytes) = variant.max_bytes else {
+            return Ok(EncodedVariant {
+                body: encode::encode(&processed_img, variant, color, metadata)?,
+                width,
+                height,
+                quality: encode::quality(variant),
+            });
+        };
+
+        let max_quality = encode::quality(variant).context("max_bytes needs a lossy format")?;
+        let smallest = match quality::fit_max_bytes(max_bytes, max_quality, encode_at)? {
+            Budget::Fits { body, quality } => {
+                return Ok(EncodedVariant { body, width, height, quality: Some(quality) });
+            }
+            Budget::TooLarge { smallest } => smallest,
+        };
+
+        // Size scales roughly with area, so aim a little under the budget
+        let factor = ((max_bytes as f64 / smallest as f64).sqrt() * 0.95).clamp(0.5, 0.9);
+        let current = spec.unwrap_or(ResizeSpec {
+            width,
+            height: None,
+            fit: Fit::Exact,
+            linear_light: variant.linear_light.unwrap_or(variant.is_lossy()),
+        });
+        let min_quality = max_quality.min(quality::MIN_QUALITY);
+        if current.width <= MIN_BUDGET_WIDTH {
+            anyhow::bail!("{} bytes is too small even at {}px wide and quality {}", max_bytes, width, min_quality);
+        }
+        tracing::debug!("{} is {} bytes at quality {}, shrinking by {:.2}", variant.name, smallest, min_quality, factor);
+        spec = Some(current.shrunk(factor));
+    }
+}
+
+/// Rasterizes the SVG at the scale the variant needs, so the resize that follows
+/// only crops or pads instead of resampling.
+fn convert_vector(vector: &VectorImage, variant: &Variant, focal: Option<FocalHint>, metadata: &Metadata) -> Result<EncodedVariant> {
+    let scale = variant.resize_spec()
+        .map_or(1.0, |spec| spec.scale_factor(vector.dimensions()));
+    let raster = vector.render(scale)?;
+    // Pixel-valued hints refer to the SVG's coordinates, not the raster's
+    let focal = focal.map(|focal| focal.relative_to(vector.intrinsic_size()));
+    convert_image(&raster, ColorProfile::Srgb, variant, focal, metadata)
 }
 
-fn convert_to_webp(img: &DynamicImage, width: Option<u32>) -> Result<Vec<u8>> {
-    let processed_img = match width {
-        Some(w) => {
-            let height = (img.height() as f64 * w as f64 / img.width() as f64) as u32;
-            img.resize(w, height, image::imageops::FilterType::Lanczos3)
+fn convert_animation(animation: &Animation, variant: &Variant, focal: Option<FocalHint>) -> Result<EncodedVariant> {
+    let resized;
+    let frames = match variant.resize_spec() {
+        Some(spec) => {
+            resized = animation.resize(&spec, variant, focal);
+            &resized
         }
-        None => img.clone(),
+        None => &animation.frames,
     };
+    let (width, height) = frames[0].image.dimensions();
+    Ok(EncodedVariant {
+        body: encode::encode_animated_webp(frames, animation.loop_count, variant)?,
+        width,
+        height,
+        quality: encode::quality(variant),
+    })
+}
 
-    let mut buffer = Vec::new();
-    let mut cursor = Cursor::new(&mut buffer);
+async fn put_variant_object(
+    s3_client: &S3Client,
+    bucket_name: &str,
+    key: &str,
+    source_key: &str,
+    format: OutputFormat,
+    encoded: EncodedVariant,
+    user_metadata: &[(&str, String)],
+) -> Result<()> {
+    let mut request = s3_client
+        .put_object()
+        .bucket(bucket_name)
+        .key(key)
+        .body(ByteStream::from(encoded.body))
+        .content_type(format.content_type())
+        .cache_control("public, max-age=31536000, immutable")
+        .metadata(GENERATED_FROM_METADATA, urlencoding::encode(source_key))
+        .tagging(GENERATED_TAG);
+    if let Some(quality) = encoded.quality {
+        request = request.metadata(QUALITY_METADATA, quality.to_string());
+    }
+    request = request
+        .metadata(WIDTH_METADATA, encoded.width.to_string())
+        .metadata(HEIGHT_METADATA, encoded.height.to_string());
+    for (name, value) in user_metadata {
+        request = request.metadata(*name, value);
+    }
 
-    processed_img.write_to(&mut cursor, ImageFormat::WebP)
-        .context("Failed to encode image as WebP")?;
+    request
+        .send()
+        .await
+        .context("Failed to put image object to S3")?;
 
-    Ok(buffer)
+    Ok(())
 }
 
-async fn put_webp_object(
+/// Writes a JSON document describing the source's variants. Unlike the variants
+/// it is rewritten whenever the source is, so it isn't cached as immutable.
+async fn put_sidecar_object(
     s3_client: &S3Client,
     bucket_name: &str,
     key: &str,
+    source_key: &str,
     body: Vec<u8>,
 ) -> Result<()> {
     s3_client
@@ -230,11 +850,13 @@ async fn put_webp_object(
         .bucket(bucket_name)
         .key(key)
         .body(ByteStream::from(body))
-        .content_type("image/webp")
-        .cache_control("public, max-age=31536000, immutable")
+        .content_type("application/json")
+        .cache_control("public, max-age=60")
+        .metadata(GENERATED_FROM_METADATA, urlencoding::encode(source_key))
+        .tagging(GENERATED_TAG)
         .send()
         .await
-        .context("Failed to put WebP object to S3")?;
+        .context("Failed to put sidecar object to S3")?;
 
     Ok(())
 }
@@ -242,5 +864,11 @@ async fn put_webp_object(
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     tracing_subscriber::fmt::init();
-    run(service_fn(function_handler)).await
+
+    let aws_config = aws_config::load_defaults(BehaviorVersion::latest()).await;
+    let s3_client = S3Client::new(&aws_config);
+    let config = Config::load(&s3_client).await?;
+    tracing::info!("Loaded {} image variants", config.variants.len());
+
+    run(service_fn(|event| function_handler(&s3_client, &config, event))).await
 }
@@ -0,0 +1,259 @@
+use anyhow::{Context, Result};
+use exif::experimental::Writer;
+use exif::{Field, In, Tag};
+use roxmltree::Document;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::ops::Range;
+
+const RDF_NS: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+const XMP_RIGHTS_NS: &str = "http://ns.adobe.com/xap/1.0/rights/";
+const PLUS_NS: &str = "http://ns.useplus.org/ldf/xmp/1.0/";
+const PHOTOSHOP_NS: &str = "http://ns.adobe.com/photoshop/1.0/";
+const EXIF_NS: &str = "http://ns.adobe.com/exif/1.0/";
+const TIFF_NS: &str = "http://ns.adobe.com/tiff/1.0/";
+const IPTC_CORE_NS: &str = "http://iptc.org/std/Iptc4xmpCore/1.0/xmlns/";
+const IPTC_EXT_NS: &str = "http://iptc.org/std/Iptc4xmpExt/2008-02-29/";
+
+/// EXIF tags describing the source's pixel layout or orientation, which no
+/// longer hold for a resized, upright variant.
+const LAYOUT_TAGS: [Tag; 13] = [
+    Tag::ImageWidth,
+    Tag::ImageLength,
+    Tag::BitsPerSample,
+    Tag::Compression,
+    Tag::PhotometricInterpretation,
+    Tag::Orientation,
+    Tag::SamplesPerPixel,
+    Tag::PlanarConfiguration,
+    Tag::RowsPerStrip,
+    Tag::YCbCrSubSampling,
+    Tag::PixelXDimension,
+    Tag::PixelYDimension,
+    Tag::MakerNote,
+];
+
+/// Which of the source's EXIF and XMP metadata a variant keeps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetadataPolicy {
+    /// Drop all metadata.
+    #[default]
+    Strip,
+    /// Keep only the EXIF copyright and artist, and XMP rights and creator.
+    Copyright,
+    /// Keep everything except GPS coordinates and location names.
+    ScrubGps,
+}
+
+/// Metadata to embed in a variant, already filtered by its policy.
+#[derive(Debug, Default)]
+pub struct Metadata {
+    /// A TIFF structure, without the `Exif\0\0` header JPEG puts in front of it.
+    pub exif: Option<Vec<u8>>,
+    /// An `x:xmpmeta` XML document.
+    pub xmp: Option<String>,
+}
+
+/// EXIF and XMP read once from the source, filtered for each variant.
+#[derive(Default)]
+pub struct SourceMetadata {
+    /// Fields of the primary image; the thumbnail's are never carried over.
+    exif_fields: Vec<Field>,
+    little_endian: bool,
+    xmp: Option<String>,
+}
+
+impl SourceMetadata {
+    pub fn read(bytes: &[u8]) -> Self {
+        let exif = exif::Reader::new().read_from_container(&mut Cursor::new(bytes)).ok();
+        SourceMetadata {
+            exif_fields: exif
+                .iter()
+                .flat_map(|exif| exif.fields())
+                .filter(|field| field.ifd_num == In::PRIMARY)
+                .cloned()
+                .collect(),
+            little_endian: exif.as_ref().is_some_and(|exif| exif.little_endian()),
+            xmp: find_xmp(bytes),
+        }
+    }
+
+    pub fn filter(&self, policy: MetadataPolicy) -> Result<Metadata> {
+        if policy == MetadataPolicy::Strip {
+            return Ok(Metadata::default());
+        }
+
+        let exif = write_exif(&self.exif_fields, self.little_endian, policy)?;
+        let xmp = match &self.xmp {
+            Some(xmp) => filter_xmp(xmp, policy)?,
+            None => None,
+        };
+        Ok(Metadata { exif, xmp })
+    }
+}
+
+/// XMP packets are stored as plain text in every container we read (JPEG APP1,
+/// PNG iTXt, WebP XMP chunk, TIFF tag 700, HEIF mime item), so the packet is
+/// found by its root element rather than by parsing each container.
+fn find_xmp(bytes: &[u8]) -> Option<String> {
+    const START: &[u8] = b"<x:xmpmeta";
+    const END: &[u8] = b"</x:xmpmeta>";
+
+    let start = bytes.windows(START.len()).position(|w| w == START)?;
+    let length = bytes[start..].windows(END.len()).position(|w| w == END)? + END.len();
+    String::from_utf8(bytes[start..start + length].to_vec()).ok()
+}
+
+fn write_exif(fields: &[Field], little_endian: bool, policy: MetadataPolicy) -> Result<Option<Vec<u8>>> {
+    let mut seen = HashSet::new();
+    let fields: Vec<&Field> = fields
+        .iter()
+        .filter(|field| keep_exif_tag(field.tag, policy))
+        .filter(|field| seen.insert(field.tag))
+        .collect();
+    if fields.is_empty() {
+        return Ok(None);
+    }
+
+    let mut writer = Writer::new();
+    for field in fields {
+        writer.push_field(field);
+    }
+    let mut buffer = Cursor::new(Vec::new());
+    writer
+        .write(&mut buffer, little_endian)
+        .context("Failed to write EXIF data")?;
+    Ok(Some(buffer.into_inner()))
+}
+
+fn keep_exif_tag(tag: Tag, policy: MetadataPolicy) -> bool {
+    match policy {
+        MetadataPolicy::Strip => false,
+        MetadataPolicy::Copyright => matches!(tag, Tag::Copyright | Tag::Artist),
+        // Unknown tags are mostly vendor and RAW structures holding file offsets
+        // that would dangle once rewritten
+        MetadataPolicy::ScrubGps => {
+            tag.context() != exif::Context::Gps
+                && tag.description().is_some()
+                && !LAYOUT_TAGS.contains(&tag)
+        }
+    }
+}
+
+/// Removes the properties the policy doesn't keep from every `rdf:Description`,
+/// leaving the rest of the packet as it was. Returns `None` when nothing is left.
+fn filter_xmp(xmp: &str, policy: MetadataPolicy) -> Result<Option<String>> {
+    let document = Document::parse(xmp).context("Failed to parse XMP")?;
+
+    let mut removed: Vec<Range<usize>> = Vec::new();
+    let mut kept = 0;
+    let is_rdf = |node: roxmltree::Node, name: &str| {
+        node.tag_name().namespace() == Some(RDF_NS) && node.tag_name().name() == name
+    };
+    // Only top-level descriptions hold properties; nested ones are struct values
+    let descriptions = document
+        .descendants()
+        .filter(|node| is_rdf(*node, "Description") && node.parent_element().is_some_and(|parent| is_rdf(parent, "RDF")));
+    for description in descriptions {
+        // Simple properties can be written as attributes of the description
+        for attribute in description.attributes() {
+            match attribute.namespace() {
+                Some(RDF_NS) | None => {}
+                Some(namespace) if keep_xmp_property(namespace, attribute.name(), policy) => kept += 1,
+                Some(_) => removed.push(attribute.range()),
+            }
+        }
+
+        for property in description.children().filter(|node| node.is_element()) {
+            let name = property.tag_name();
+            match name.namespace() {
+                Some(namespace) if keep_xmp_property(namespace, name.name(), policy) => kept += 1,
+                _ => removed.push(property.range()),
+            }
+        }
+    }
+
+    if kept == 0 {
+        return Ok(None);
+    }
+
+    let mut filtered = xmp.to_string();
+    removed.sort_by_key(|range| std::cmp::Reverse(range.start));
+    for range in removed {
+        filtered.replace_range(range, "");
+    }
+    Ok(Some(filtered))
+}
+
+fn keep_xmp_property(namespace: &str, name: &str, policy: MetadataPolicy) -> bool {
+    match policy {
+        MetadataPolicy::Strip => false,
+        MetadataPolicy::Copyright => matches!(
+            (namespace, name),
+            (DC_NS, "rights" | "creator") | (XMP_RIGHTS_NS, _) | (PLUS_NS, _) | (PHOTOSHOP_NS, "Credit")
+        ),
+        MetadataPolicy::ScrubGps => match (namespace, name) {
+            (EXIF_NS, name) if name.starts_with("GPS") => false,
+            (EXIF_NS, "PixelXDimension" | "PixelYDimension")
+            | (TIFF_NS, "Orientation" | "ImageWidth" | "ImageLength")
+            | (PHOTOSHOP_NS, "City" | "State" | "Country")
+            | (IPTC_CORE_NS, "Location" | "CountryCode")
+            | (IPTC_EXT_NS, "LocationCreated" | "LocationShown") => false,
+            _ => true,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XMP: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:exif="http://ns.adobe.com/exif/1.0/"
+    xmlns:photoshop="http://ns.adobe.com/photoshop/1.0/"
+    photoshop:City="Lisbon"
+    photoshop:Credit="Example Agency">
+   <dc:creator><rdf:Seq><rdf:li>Jane Doe</rdf:li></rdf:Seq></dc:creator>
+   <dc:rights><rdf:Alt><rdf:li xml:lang="x-default">Jane Doe</rdf:li></rdf:Alt></dc:rights>
+   <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Harbour</rdf:li></rdf:Alt></dc:title>
+   <exif:GPSLatitude>38,42.5N</exif:GPSLatitude>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>"#;
+
+    /// Names of the properties left in the filtered packet, which must still parse.
+    fn properties(xmp: &str) -> Vec<String> {
+        let document = Document::parse(xmp).unwrap();
+        let description = document.descendants().find(|node| node.tag_name().name() == "Description").unwrap();
+        let attributes = description
+            .attributes()
+            .filter(|attribute| attribute.namespace() != Some(RDF_NS))
+            .map(|attribute| attribute.name().to_string());
+        let elements = description.children().filter(|node| node.is_element()).map(|node| node.tag_name().name().to_string());
+        attributes.chain(elements).collect()
+    }
+
+    #[test]
+    fn copyright_keeps_rights_and_creator() {
+        let filtered = filter_xmp(XMP, MetadataPolicy::Copyright).unwrap().unwrap();
+        assert_eq!(properties(&filtered), ["Credit", "creator", "rights"]);
+    }
+
+    #[test]
+    fn scrub_gps_drops_location() {
+        let filtered = filter_xmp(XMP, MetadataPolicy::ScrubGps).unwrap().unwrap();
+        assert_eq!(properties(&filtered), ["Credit", "creator", "rights", "title"]);
+    }
+
+    #[test]
+    fn nothing_kept_is_none() {
+        let location = XMP.replace("dc:creator", "dc:coverage").replace("dc:rights", "dc:subject").replace("photoshop:Credit", "photoshop:State");
+        assert_eq!(filter_xmp(&location, MetadataPolicy::Copyright).unwrap(), None);
+    }
+}
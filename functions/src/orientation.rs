@@ -0,0 +1,60 @@
+use anyhow::{Context, Result};
+use exif::{In, Tag};
+use image::DynamicImage;
+use std::io::Cursor;
+
+/// EXIF Orientation tag values 2-8: how the stored pixels must be transformed to
+/// display upright. 1 (already upright) is represented by not having one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    FlipHorizontal,
+    Rotate180,
+    FlipVertical,
+    Transpose,
+    Rotate90,
+    Transverse,
+    Rotate270,
+}
+
+impl Orientation {
+    /// Reads the orientation of the primary image from EXIF data in a JPEG, TIFF
+    /// (including TIFF-based RAW), PNG, WebP or HEIF container.
+    pub fn from_exif(bytes: &[u8]) -> Result<Option<Self>> {
+        let exif = match exif::Reader::new().read_from_container(&mut Cursor::new(bytes)) {
+            Ok(exif) => exif,
+            // No EXIF block, or a container that can't carry or doesn't parse as
+            // one (GIF, BMP, SVG...), which is no orientation rather than an error
+            Err(exif::Error::NotFound(_) | exif::Error::InvalidFormat(_)) => return Ok(None),
+            Err(e) => return Err(e).context("Failed to read EXIF data"),
+        };
+
+        let value = exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|field| field.value.get_uint(0));
+
+        Ok(match value {
+            Some(2) => Some(Orientation::FlipHorizontal),
+            Some(3) => Some(Orientation::Rotate180),
+            Some(4) => Some(Orientation::FlipVertical),
+            Some(5) => Some(Orientation::Transpose),
+            Some(6) => Some(Orientation::Rotate90),
+            Some(7) => Some(Orientation::Transverse),
+            Some(8) => Some(Orientation::Rotate270),
+            _ => None,
+        })
+    }
+
+    /// Transforms stored pixels into their upright orientation. Rotations are
+    /// clockwise.
+    pub fn apply(self, img: &DynamicImage) -> DynamicImage {
+        match self {
+            Orientation::FlipHorizontal => img.fliph(),
+            Orientation::Rotate180 => img.rotate180(),
+            Orientation::FlipVertical => img.flipv(),
+            Orientation::Transpose => img.rotate90().fliph(),
+            Orientation::Rotate90 => img.rotate90(),
+            Orientation::Transverse => img.rotate270().fliph(),
+            Orientation::Rotate270 => img.rotate270(),
+        }
+    }
+}
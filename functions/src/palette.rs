@@ -0,0 +1,105 @@
+use anyhow::Result;
+use image::DynamicImage;
+use serde::Serialize;
+
+use crate::color::{self, ColorProfile};
+
+/// Palettes come from a thumbnail; more pixels only slow the clustering down.
+const THUMBNAIL_SIZE: u32 = 64;
+const PALETTE_SIZE: usize = 5;
+const ITERATIONS: usize = 16;
+/// Pixels more transparent than this don't count towards any color.
+const MIN_ALPHA: u8 = 128;
+
+/// The main colors of an image as `#rrggbb` sRGB, most common first.
+#[derive(Debug, Clone, Serialize)]
+pub struct Palette {
+    /// The most common color, for use as a background while the image loads.
+    pub dominant_color: String,
+    /// Up to five colors including the dominant one.
+    pub palette: Vec<String>,
+}
+
+impl Palette {
+    /// Clusters the colors of `img`, whose pixels are in the `color` space, with
+    /// k-means. Returns `None` for images without any visible pixels.
+    pub fn extract(img: &DynamicImage, color: ColorProfile) -> Result<Option<Self>> {
+        let thumbnail = color::to_srgb(&img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE), color)?.to_rgba8();
+        let pixels: Vec<[f32; 3]> = thumbnail
+            .pixels()
+            .filter(|pixel| pixel[3] >= MIN_ALPHA)
+            .map(|pixel| [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32])
+            .collect();
+        if pixels.is_empty() {
+            return Ok(None);
+        }
+
+        let mut clusters = kmeans(&pixels);
+        clusters.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        let palette: Vec<String> = clusters.iter().map(|(center, _)| hex(*center)).collect();
+        Ok(Some(Palette {
+            dominant_color: palette[0].clone(),
+            palette,
+        }))
+    }
+}
+
+/// Returns each non-empty cluster's center and pixel count. Centers start at
+/// mutually distant pixels (maximin), which is deterministic, unlike k-means++.
+fn kmeans(pixels: &[[f32; 3]]) -> Vec<([f32; 3], usize)> {
+    let mut centers = vec![pixels[0]];
+    while centers.len() < PALETTE_SIZE {
+        let farthest = pixels
+            .iter()
+            .map(|pixel| (pixel, nearest(&centers, pixel).1))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        match farthest {
+            // Fewer distinct colors than the palette holds
+            Some((_, 0.0)) => break,
+            Some((pixel, _)) => centers.push(*pixel),
+            None => break,
+        }
+    }
+
+    let mut counts = vec![0; centers.len()];
+    for _ in 0..ITERATIONS {
+        let mut sums = vec![[0.0f32; 3]; centers.len()];
+        counts.fill(0);
+        for pixel in pixels {
+            let (index, _) = nearest(&centers, pixel);
+            for channel in 0..3 {
+                sums[index][channel] += pixel[channel];
+            }
+            counts[index] += 1;
+        }
+
+        let mut moved = false;
+        for ((center, sum), &count) in centers.iter_mut().zip(&sums).zip(&counts) {
+            if count == 0 {
+                continue;
+            }
+            let mean = sum.map(|total| total / count as f32);
+            moved |= mean != *center;
+            *center = mean;
+        }
+        if !moved {
+            break;
+        }
+    }
+
+    centers.into_iter().zip(counts).filter(|(_, count)| *count > 0).collect()
+}
+
+fn nearest(centers: &[[f32; 3]], pixel: &[f32; 3]) -> (usize, f32) {
+    centers
+        .iter()
+        .map(|center| (0..3).map(|c| (center[c] - pixel[c]).powi(2)).sum::<f32>())
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((0, 0.0))
+}
+
+fn hex(color: [f32; 3]) -> String {
+    let [r, g, b] = color.map(|channel| channel.round().clamp(0.0, 255.0) as u8);
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
@@ -0,0 +1,93 @@
+use image::DynamicImage;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::f32::consts::PI;
+
+/// pHash is computed from the low frequencies of a DCT of this many pixels square.
+const PHASH_SIZE: usize = 32;
+/// Hashes of near-duplicates (recompressed, resized or lightly edited copies)
+/// differ in at most this many of their 64 bits.
+const DUPLICATE_DISTANCE: u32 = 4;
+
+/// Difference and DCT hashes of an image, which stay the same through resizing
+/// and recompression. Written as 16 hex digits each.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PerceptualHash {
+    #[serde(with = "hex")]
+    pub dhash: u64,
+    #[serde(with = "hex")]
+    pub phash: u64,
+}
+
+impl PerceptualHash {
+    pub fn compute(img: &DynamicImage) -> Self {
+        PerceptualHash {
+            dhash: dhash(img),
+            phash: phash(img),
+        }
+    }
+
+    /// Both hashes must agree, so a chance dHash collision between two different
+    /// images with similar gradients isn't enough.
+    pub fn is_near(&self, other: &PerceptualHash) -> bool {
+        (self.dhash ^ other.dhash).count_ones() <= DUPLICATE_DISTANCE
+            && (self.phash ^ other.phash).count_ones() <= DUPLICATE_DISTANCE
+    }
+
+    /// Total differing bits across both hashes.
+    pub fn distance(&self, other: &PerceptualHash) -> u32 {
+        (self.dhash ^ other.dhash).count_ones() + (self.phash ^ other.phash).count_ones()
+    }
+}
+
+/// One bit per pair of horizontally adjacent pixels of a 9x8 grayscale
+/// thumbnail: set when brightness decreases to the right.
+fn dhash(img: &DynamicImage) -> u64 {
+    let gray = img.thumbnail_exact(9, 8).to_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash = hash << 1 | (gray.get_pixel(x, y)[0] > gray.get_pixel(x + 1, y)[0]) as u64;
+        }
+    }
+    hash
+}
+
+/// One bit per coefficient of the 8x8 lowest frequencies of a DCT of a 32x32
+/// grayscale thumbnail: set when it is above the median, not counting the DC
+/// term, which only measures overall brightness.
+fn phash(img: &DynamicImage) -> u64 {
+    let gray = img.thumbnail_exact(PHASH_SIZE as u32, PHASH_SIZE as u32).to_luma8();
+    let pixels: Vec<f32> = gray.pixels().map(|pixel| pixel[0] as f32).collect();
+    let basis = |frequency: usize, position: usize| {
+        ((2 * position + 1) as f32 * frequency as f32 * PI / (2 * PHASH_SIZE) as f32).cos()
+    };
+
+    let mut coefficients = [0.0f32; 64];
+    for (i, coefficient) in coefficients.iter_mut().enumerate() {
+        let (u, v) = (i % 8, i / 8);
+        for y in 0..PHASH_SIZE {
+            let row = basis(v, y);
+            for x in 0..PHASH_SIZE {
+                *coefficient += pixels[y * PHASH_SIZE + x] * basis(u, x) * row;
+            }
+        }
+    }
+
+    let mut ac = coefficients[1..].to_vec();
+    ac.sort_by(f32::total_cmp);
+    let median = ac[ac.len() / 2];
+    coefficients.iter().fold(0, |hash, &c| hash << 1 | (c > median) as u64)
+}
+
+mod hex {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:016x}", value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        u64::from_str_radix(&text, 16).map_err(serde::de::Error::custom)
+    }
+}
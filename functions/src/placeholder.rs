@@ -0,0 +1,50 @@
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use image::DynamicImage;
+use serde::Serialize;
+
+use crate::color::{self, ColorProfile};
+
+/// ThumbHash only accepts images up to 100x100, and neither hash gains anything
+/// from more pixels.
+const THUMBNAIL_SIZE: u32 = 100;
+/// BlurHash components along the longer edge of the image; the shorter edge gets
+/// proportionally fewer, down to 3.
+const BLURHASH_COMPONENTS: u32 = 4;
+
+/// Low-resolution placeholders rendered by the frontend while the image loads.
+#[derive(Debug, Clone, Serialize)]
+pub struct Placeholders {
+    pub blurhash: String,
+    /// Base64 of the ThumbHash bytes, as its reference decoder expects.
+    pub thumbhash: String,
+}
+
+impl Placeholders {
+    /// Computes both hashes from a thumbnail of `img`, whose pixels are in the
+    /// `color` space. Placeholders are always sRGB, like the CSS they turn into.
+    pub fn compute(img: &DynamicImage, color: ColorProfile) -> Result<Self> {
+        let thumbnail = color::to_srgb(&img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE), color)?.to_rgba8();
+        let (width, height) = thumbnail.dimensions();
+
+        let components = |edge: u32, longer: u32| {
+            (BLURHASH_COMPONENTS * edge / longer).max(3)
+        };
+        let longer = width.max(height);
+        let blurhash = blurhash::encode(
+            components(width, longer),
+            components(height, longer),
+            width,
+            height,
+            thumbnail.as_raw(),
+        )
+        .map_err(|e| anyhow!("Failed to compute BlurHash: {:?}", e))?;
+
+        let thumbhash = thumbhash::rgba_to_thumb_hash(width as usize, height as usize, thumbnail.as_raw());
+        Ok(Placeholders {
+            blurhash,
+            thumbhash: STANDARD.encode(thumbhash),
+        })
+    }
+}
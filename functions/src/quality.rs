@@ -0,0 +1,87 @@
+use anyhow::Result;
+use image::DynamicImage;
+
+use crate::ssim::Reference;
+
+/// Lowest quality either search goes down to. Below it artifacts are worse than a
+/// smaller image, so `max_bytes` shrinks the variant instead. Variants configured
+/// below it are never raised to it.
+pub const MIN_QUALITY: u8 = 30;
+
+/// Result of searching for the highest quality that fits a byte budget.
+pub enum Budget {
+    Fits { body: Vec<u8>, quality: u8 },
+    /// Even the lowest quality searched is too large; `smallest` is its size in bytes.
+    TooLarge { smallest: usize },
+}
+
+/// Binary-searches qualities from `MIN_QUALITY`, or `max_quality` if that's lower,
+/// to `max_quality` for the highest one whose output is at most `max_bytes`.
+/// Output size isn't strictly monotonic in quality, but close enough that a search
+/// beats encoding every step.
+pub fn fit_max_bytes(max_bytes: u64, max_quality: u8, encode: impl Fn(u8) -> Result<Vec<u8>>) -> Result<Budget> {
+    let min_quality = max_quality.min(MIN_QUALITY);
+    let body = encode(max_quality)?;
+    if body.len() as u64 <= max_bytes {
+        return Ok(Budget::Fits { body, quality: max_quality });
+    }
+    if min_quality == max_quality {
+        return Ok(Budget::TooLarge { smallest: body.len() });
+    }
+
+    let smallest = encode(min_quality)?;
+    if smallest.len() as u64 > max_bytes {
+        return Ok(Budget::TooLarge { smallest: smallest.len() });
+    }
+
+    // Invariant: `low` fits and `high` doesn't
+    let (mut low, mut high) = (min_quality, max_quality);
+    let mut best = smallest;
+    while high - low > 1 {
+        let quality = low + (high - low) / 2;
+        let body = encode(quality)?;
+        if body.len() as u64 <= max_bytes {
+            low = quality;
+            best = body;
+        } else {
+            high = quality;
+        }
+    }
+    Ok(Budget::Fits { body: best, quality: low })
+}
+
+/// Binary-searches qualities from `MIN_QUALITY`, or `max_quality` if that's lower,
+/// to `max_quality` for the lowest one whose decoded output is within `max_dssim` of `reference`. Falls back to
+/// `max_quality` when even that is further off.
+pub fn auto_quality(
+    reference: &DynamicImage,
+    max_dssim: f64,
+    max_quality: u8,
+    encode: impl Fn(u8) -> Result<Vec<u8>>,
+    decode: impl Fn(&[u8]) -> Result<DynamicImage>,
+) -> Result<u8> {
+    let reference = Reference::new(reference);
+    let passes = |quality: u8| -> Result<bool> {
+        let decoded = decode(&encode(quality)?)?;
+        let dssim = reference.dssim(&decoded);
+        tracing::debug!("Quality {} has DSSIM {:.5}", quality, dssim);
+        Ok(dssim <= max_dssim)
+    };
+
+    let min_quality = max_quality.min(MIN_QUALITY);
+    if min_quality == max_quality || passes(min_quality)? {
+        return Ok(min_quality);
+    }
+
+    // Invariant: `low` fails and `high` passes, or is `max_quality`
+    let (mut low, mut high) = (min_quality, max_quality);
+    while high - low > 1 {
+        let quality = low + (high - low) / 2;
+        if passes(quality)? {
+            high = quality;
+        } else {
+            low = quality;
+        }
+    }
+    Ok(high)
+}
@@ -0,0 +1,203 @@
+use anyhow::{anyhow, Context, Result};
+use image::{DynamicImage, RgbImage};
+use rawloader::{RawImage, RawImageData};
+use std::io::Cursor;
+
+use crate::resample;
+
+/// sRGB (D65) primaries to XYZ, as used by dcraw and rawloader.
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.412453, 0.357580, 0.180423],
+    [0.212671, 0.715160, 0.072169],
+    [0.019334, 0.119193, 0.950227],
+];
+/// Strength of the S-curve applied after gamma, from 0 (none) to 1 (smoothstep).
+const TONE_CONTRAST: f32 = 0.25;
+
+/// Most RAW formats (DNG, CR2, NEF, ARW, PEF...) are TIFF containers; the rest
+/// have their own signatures. Plain TIFFs match too, so callers fall back to
+/// the regular decoders when the RAW decoder rejects the file.
+pub fn is_raw(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"II*\0")
+        || bytes.starts_with(b"MM\0*")
+        || bytes.starts_with(b"IIRO") // Olympus ORF
+        || bytes.starts_with(b"IIRS") // Olympus ORF
+        || bytes.starts_with(b"IIU\0") // Panasonic RW2
+        || bytes.starts_with(b"FUJIFILM") // Fujifilm RAF
+}
+
+/// Develops a RAW file into an 8-bit sRGB image: black/white level scaling,
+/// white balance from the camera metadata, bilinear demosaic, camera to sRGB
+/// color conversion and a basic tone curve.
+pub fn decode(bytes: &[u8]) -> Result<DynamicImage> {
+    let raw = rawloader::decode(&mut Cursor::new(bytes))
+        .map_err(|e| anyhow!("Failed to read RAW file: {}", e))?;
+
+    let developer = Developer::new(&raw)?;
+    let [top, right, bottom, left] = raw.crops;
+    let width = raw.width.saturating_sub(left + right);
+    let height = raw.height.saturating_sub(top + bottom);
+    anyhow::ensure!(width > 0 && height > 0, "RAW crop leaves no pixels");
+
+    let mut pixels = Vec::with_capacity(width * height * 3);
+    for row in top..top + height {
+        for col in left..left + width {
+            let camera = developer.camera_rgb(row, col);
+            pixels.extend(developer.to_srgb(camera).map(|v| (v * 255.0).round() as u8));
+        }
+    }
+
+    RgbImage::from_raw(width as u32, height as u32, pixels)
+        .map(DynamicImage::ImageRgb8)
+        .context("Developed RAW image doesn't match its dimensions")
+}
+
+struct Developer<'a> {
+    raw: &'a RawImage,
+    samples: Samples<'a>,
+    /// White balance multipliers per CFA color, normalized to green
+    white_balance: [f32; 4],
+    /// Camera RGB(E) to linear sRGB, mapping white to white
+    srgb_from_camera: [[f32; 4]; 3],
+}
+
+enum Samples<'a> {
+    Integer(&'a [u16]),
+    Float(&'a [f32]),
+}
+
+impl<'a> Developer<'a> {
+    fn new(raw: &'a RawImage) -> Result<Self> {
+        if raw.cpp != 1 && raw.cpp != 3 {
+            anyhow::bail!("Unsupported RAW layout with {} components per pixel", raw.cpp);
+        }
+        if raw.cpp == 1 && !raw.cfa.is_valid() && !raw.is_monochrome() {
+            anyhow::bail!("RAW file has no usable color filter array");
+        }
+
+        let samples = match &raw.data {
+            RawImageData::Integer(data) => Samples::Integer(data),
+            RawImageData::Float(data) => Samples::Float(data),
+        };
+        if samples.len() < raw.width * raw.height * raw.cpp {
+            anyhow::bail!("RAW data is shorter than its dimensions");
+        }
+
+        // Fall back to a D65 neutral balance when the camera didn't record one
+        let as_shot = raw.wb_coeffs;
+        let coeffs = if as_shot[..3].iter().all(|c| c.is_finite() && *c > 0.0) {
+            as_shot
+        } else {
+            raw.neutralwb()
+        };
+        let green = coeffs[1];
+        let white_balance = coeffs.map(|c| if c.is_finite() && c > 0.0 { c / green } else { 1.0 });
+
+        Ok(Developer {
+            raw,
+            samples,
+            white_balance,
+            srgb_from_camera: srgb_from_camera(raw.xyz_to_cam),
+        })
+    }
+
+    /// A white balanced sample scaled to 0.0..=1.0. Values are clipped after
+    /// balancing so blown highlights stay neutral instead of turning pink.
+    fn sample(&self, row: usize, col: usize, component: usize, color: usize) -> f32 {
+        let index = (row * self.raw.width + col) * self.raw.cpp + component;
+        let black = self.raw.blacklevels[color] as f32;
+        let white = self.raw.whitelevels[color] as f32;
+        let value = (self.samples.get(index) - black) / (white - black).max(1.0);
+        (value.max(0.0) * self.white_balance[color]).min(1.0)
+    }
+
+    /// Camera RGB(E) at a pixel, interpolating the colors a CFA sensor didn't
+    /// capture from the 3x3 neighborhood.
+    fn camera_rgb(&self, row: usize, col: usize) -> [f32; 4] {
+        if self.raw.cpp == 3 {
+            return [
+                self.sample(row, col, 0, 0),
+                self.sample(row, col, 1, 1),
+                self.sample(row, col, 2, 2),
+                0.0,
+            ];
+        }
+        if self.raw.is_monochrome() {
+            let value = self.sample(row, col, 0, 1);
+            return [value, value, value, 0.0];
+        }
+
+        let cfa = &self.raw.cfa;
+        let own = cfa.color_at(row, col);
+        let mut sums = [0.0f32; 4];
+        let mut counts = [0u32; 4];
+        for y in row.saturating_sub(1)..=(row + 1).min(self.raw.height - 1) {
+            for x in col.saturating_sub(1)..=(col + 1).min(self.raw.width - 1) {
+                let color = cfa.color_at(y, x);
+                if color == own && (y, x) != (row, col) {
+                    continue;
+                }
+                sums[color] += self.sample(y, x, 0, color);
+                counts[color] += 1;
+            }
+        }
+
+        let mut rgbe = [0.0; 4];
+        for color in 0..4 {
+            if counts[color] > 0 {
+                rgbe[color] = sums[color] / counts[color] as f32;
+            }
+        }
+        rgbe
+    }
+
+    fn to_srgb(&self, camera: [f32; 4]) -> [f32; 3] {
+        let matrix = &self.srgb_from_camera;
+        let mut linear = [0.0; 3];
+        for (channel, row) in matrix.iter().enumerate() {
+            linear[channel] = row.iter().zip(camera).map(|(m, c)| m * c).sum();
+        }
+        linear.map(|v| tone_curve(resample::encode_srgb(v.clamp(0.0, 1.0))))
+    }
+}
+
+impl Samples<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Samples::Integer(data) => data.len(),
+            Samples::Float(data) => data.len(),
+        }
+    }
+
+    fn get(&self, index: usize) -> f32 {
+        match self {
+            Samples::Integer(data) => data[index] as f32,
+            Samples::Float(data) => data[index],
+        }
+    }
+}
+
+/// Builds the camera to sRGB matrix the way dcraw does: the camera's XYZ matrix
+/// is combined with the sRGB primaries and normalized so that a white balanced
+/// neutral maps to sRGB white, then inverted. Cameras without a color matrix
+/// get their channels passed through unchanged.
+fn srgb_from_camera(xyz_to_cam: [[f32; 3]; 4]) -> [[f32; 4]; 3] {
+    let mut cam_from_srgb = [[0.0f32; 3]; 4];
+    for (cam_row, xyz_row) in cam_from_srgb.iter_mut().zip(xyz_to_cam) {
+        for (j, value) in cam_row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| xyz_row[k] * SRGB_TO_XYZ[k][j]).sum();
+        }
+    }
+
+    if cam_from_srgb[..3].iter().any(|row| row.iter().sum::<f32>() == 0.0) {
+        return [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0]];
+    }
+    RawImage::normalized_pseudoinverse(cam_from_srgb)
+}
+
+/// Gentle S-curve on gamma encoded values, since undeveloped RAW data looks
+/// flat next to the camera's own JPEGs.
+fn tone_curve(value: f32) -> f32 {
+    let smooth = value * value * (3.0 - 2.0 * value);
+    value + TONE_CONTRAST * (smooth - value)
+}
@@ -0,0 +1,182 @@
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, Rgba, RgbaImage};
+use serde::Deserialize;
+
+use crate::focal::FocalHint;
+use crate::resample;
+use crate::smartcrop;
+
+/// How an image is fitted into a `width` x `height` box.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale to fit inside the box and pad the remainder with the background color.
+    Contain,
+    /// Scale to cover the box and crop whatever overflows it.
+    Cover,
+    /// Stretch to the box, ignoring the aspect ratio.
+    Fill,
+    /// Scale to fit inside the box without cropping or padding, so the output
+    /// matches the box exactly along one edge. Width-only specs always use this.
+    #[default]
+    Exact,
+}
+
+/// Where a `cover` crop window is placed within the source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CropStrategy {
+    Center,
+    /// Pick the window from edge density, entropy and skin tones.
+    #[default]
+    Smart,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResizeSpec {
+    pub width: u32,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    /// Filter in linear light rather than on sRGB-encoded values.
+    pub linear_light: bool,
+}
+
+impl ResizeSpec {
+    /// The same spec with its box scaled down by `factor`.
+    pub fn shrunk(&self, factor: f64) -> ResizeSpec {
+        let shrink = |size: u32| ((size as f64 * factor).round() as u32).max(1);
+        ResizeSpec {
+            width: shrink(self.width),
+            height: self.height.map(shrink),
+            ..*self
+        }
+    }
+
+    /// Dimensions of the resized image for a source of the given size.
+    pub fn output_dimensions(&self, source: (u32, u32)) -> (u32, u32) {
+        let (src_w, src_h) = source;
+        let Some(height) = self.height else {
+            let height = (src_h as f64 * self.width as f64 / src_w as f64) as u32;
+            return (self.width, height.max(1));
+        };
+
+        match self.fit {
+            Fit::Contain | Fit::Cover | Fit::Fill => (self.width, height),
+            Fit::Exact => {
+                let scale = f64::min(self.width as f64 / src_w as f64, height as f64 / src_h as f64);
+                scaled(source, scale)
+            }
+        }
+    }
+
+    /// How much the source is scaled by before any crop or padding. `fill`
+    /// stretches, so it reports the larger of its two axis scales.
+    pub fn scale_factor(&self, source: (u32, u32)) -> f64 {
+        let scale_w = self.width as f64 / source.0 as f64;
+        let Some(height) = self.height else {
+            return scale_w;
+        };
+
+        let scale_h = height as f64 / source.1 as f64;
+        match self.fit {
+            Fit::Contain | Fit::Exact => scale_w.min(scale_h),
+            Fit::Cover | Fit::Fill => scale_w.max(scale_h),
+        }
+    }
+
+    /// The centered region of the source to keep before resizing, as
+    /// `(x, y, width, height)`. Only `cover` crops; every other fit uses the whole source.
+    pub fn crop_window(&self, source: (u32, u32)) -> (u32, u32, u32, u32) {
+        let (src_w, src_h) = source;
+        let height = match (self.fit, self.height) {
+            (Fit::Cover, Some(height)) => height,
+            _ => return (0, 0, src_w, src_h),
+        };
+
+        let scale = f64::max(self.width as f64 / src_w as f64, height as f64 / src_h as f64);
+        let crop_w = ((self.width as f64 / scale).round() as u32).clamp(1, src_w);
+        let crop_h = ((height as f64 / scale).round() as u32).clamp(1, src_h);
+        ((src_w - crop_w) / 2, (src_h - crop_h) / 2, crop_w, crop_h)
+    }
+}
+
+/// Resizes `img` according to `spec`, cropping or padding as the fit requires.
+/// A focal hint overrides the crop strategy so the hinted subject stays in frame.
+pub fn apply(
+    img: &DynamicImage,
+    spec: &ResizeSpec,
+    background: Rgba<u8>,
+    crop: CropStrategy,
+    focal: Option<FocalHint>,
+) -> DynamicImage {
+    let origin = crop_origin(img, spec, crop, focal);
+    apply_at(img, spec, background, origin)
+}
+
+/// Top-left corner of the `cover` crop window within `img`.
+pub fn crop_origin(
+    img: &DynamicImage,
+    spec: &ResizeSpec,
+    crop: CropStrategy,
+    focal: Option<FocalHint>,
+) -> (u32, u32) {
+    let (x, y, crop_w, crop_h) = spec.crop_window(img.dimensions());
+    if (crop_w, crop_h) == img.dimensions() {
+        return (x, y);
+    }
+
+    match (focal, crop) {
+        (Some(focal), _) => focal.crop_origin(img.dimensions(), crop_w, crop_h),
+        (None, CropStrategy::Smart) => smartcrop::find_crop(img, crop_w, crop_h),
+        (None, CropStrategy::Center) => (x, y),
+    }
+}
+
+/// Like [`apply`], with the `cover` crop window at an origin chosen up front, so
+/// every frame of an animation is cropped the same way.
+pub fn apply_at(
+    img: &DynamicImage,
+    spec: &ResizeSpec,
+    background: Rgba<u8>,
+    origin: (u32, u32),
+) -> DynamicImage {
+    let (width, height) = spec.output_dimensions(img.dimensions());
+
+    if spec.fit == Fit::Cover && spec.height.is_some() {
+        let (_, _, crop_w, crop_h) = spec.crop_window(img.dimensions());
+        let cropped = img.crop_imm(origin.0, origin.1, crop_w, crop_h);
+        return resize_to(&cropped, width, height, spec.linear_light);
+    }
+
+    if spec.fit == Fit::Contain && spec.height.is_some() {
+        let scale = f64::min(width as f64 / img.width() as f64, height as f64 / img.height() as f64);
+        let (inner_w, inner_h) = scaled(img.dimensions(), scale);
+        let inner = resize_to(img, inner_w.min(width), inner_h.min(height), spec.linear_light).to_rgba8();
+
+        let mut canvas = RgbaImage::from_pixel(width, height, background);
+        let x = (width - inner.width()) / 2;
+        let y = (height - inner.height()) / 2;
+        imageops::overlay(&mut canvas, &inner, x as i64, y as i64);
+        return DynamicImage::ImageRgba8(canvas);
+    }
+
+    resize_to(img, width, height, spec.linear_light)
+}
+
+fn resize_to(img: &DynamicImage, width: u32, height: u32, linear_light: bool) -> DynamicImage {
+    if (width, height) == img.dimensions() {
+        return img.clone();
+    }
+
+    // Images with alpha are always resampled premultiplied
+    if linear_light || img.color().has_alpha() {
+        return resample::resize(img, width, height, linear_light);
+    }
+    img.resize_exact(width, height, FilterType::Lanczos3)
+}
+
+fn scaled(source: (u32, u32), scale: f64) -> (u32, u32) {
+    let width = (source.0 as f64 * scale).round() as u32;
+    let height = (source.1 as f64 * scale).round() as u32;
+    (width.max(1), height.max(1))
+}
@@ -0,0 +1,75 @@
+use image::imageops;
+use image::{DynamicImage, ImageBuffer, Rgba};
+use serde::Deserialize;
+
+type FloatImage = ImageBuffer<Rgba<f32>, Vec<f32>>;
+
+/// Unsharp mask applied to a variant after resizing, written as
+/// `sharpen = { amount = 0.5, radius = 0.5, threshold = 2 }` in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sharpen {
+    /// How much of the difference from the blurred image is added back, e.g. 0.5 for 50%.
+    #[serde(default = "default_amount")]
+    pub amount: f32,
+    /// Standard deviation of the Gaussian blur in output pixels.
+    #[serde(default = "default_radius")]
+    pub radius: f32,
+    /// Differences below this (0-255) are left alone, so flat areas and noise
+    /// aren't sharpened.
+    #[serde(default)]
+    pub threshold: u8,
+}
+
+fn default_amount() -> f32 {
+    0.5
+}
+
+fn default_radius() -> f32 {
+    0.5
+}
+
+impl Sharpen {
+    /// Sharpens the color channels of `img`, leaving alpha untouched. Colors are
+    /// premultiplied first, so transparent pixels don't leave halos along edges.
+    pub fn apply(&self, img: &DynamicImage) -> DynamicImage {
+        if self.amount <= 0.0 || self.radius <= 0.0 {
+            return img.clone();
+        }
+
+        let has_alpha = img.color().has_alpha();
+        let mut premultiplied = FloatImage::new(img.width(), img.height());
+        for (output, Rgba([r, g, b, a])) in premultiplied.pixels_mut().zip(img.to_rgba8().pixels()) {
+            let alpha = *a as f32 / 255.0;
+            let premultiply = |value: u8| value as f32 / 255.0 * alpha;
+            *output = Rgba([premultiply(*r), premultiply(*g), premultiply(*b), alpha]);
+        }
+        let blurred = imageops::blur(&premultiplied, self.radius);
+
+        let threshold = self.threshold as f32 / 255.0;
+        let pixels: Vec<u8> = premultiplied
+            .pixels()
+            .zip(blurred.pixels())
+            .flat_map(|(&Rgba([r, g, b, alpha]), &Rgba(blur))| {
+                let sharpen = |value: f32, blurred: f32| {
+                    let difference = value - blurred;
+                    let value = if difference.abs() < threshold { value } else { value + self.amount * difference };
+                    match alpha {
+                        0.0 => 0,
+                        _ => (value / alpha * 255.0).round().clamp(0.0, 255.0) as u8,
+                    }
+                };
+                let alpha_u8 = (alpha * 255.0).round() as u8;
+                [sharpen(r, blur[0]), sharpen(g, blur[1]), sharpen(b, blur[2]), alpha_u8]
+            })
+            .collect();
+
+        let rgba = ImageBuffer::from_raw(img.width(), img.height(), pixels).expect("buffer matches its dimensions");
+        let rgba = DynamicImage::ImageRgba8(rgba);
+        if has_alpha {
+            rgba
+        } else {
+            DynamicImage::ImageRgb8(rgba.to_rgb8())
+        }
+    }
+}
@@ -0,0 +1,175 @@
+//! Content-aware crop placement for `cover` variants.
+//!
+//! The source is downsampled and every pixel gets a saliency score from edge
+//! strength, local luma entropy and skin-tone likelihood. The crop window keeps
+//! the size `cover` asks for and slides along the axis that overflows, settling
+//! where the center-weighted saliency is highest.
+
+use image::{DynamicImage, GenericImageView, GrayImage, RgbImage};
+
+/// Longest edge of the image the scores are computed on.
+const ANALYSIS_SIZE: u32 = 256;
+/// Side length of the square cells used for the entropy term.
+const ENTROPY_CELL: u32 = 8;
+
+const EDGE_WEIGHT: f32 = 1.0;
+const ENTROPY_WEIGHT: f32 = 0.4;
+const SKIN_WEIGHT: f32 = 1.8;
+
+const SKIN_COLOR: [f32; 3] = [0.78, 0.57, 0.44];
+const SKIN_THRESHOLD: f32 = 0.8;
+
+/// Returns the top-left corner, in source pixels, of the most interesting
+/// `crop_w` x `crop_h` window.
+pub fn find_crop(img: &DynamicImage, crop_w: u32, crop_h: u32) -> (u32, u32) {
+    let (src_w, src_h) = img.dimensions();
+    let free_x = src_w.saturating_sub(crop_w);
+    let free_y = src_h.saturating_sub(crop_h);
+    if free_x == 0 && free_y == 0 {
+        return (0, 0);
+    }
+
+    let small = img.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE).to_rgb8();
+    let saliency = saliency_map(&small);
+    let (small_w, small_h) = small.dimensions();
+    let scale = small_w as f64 / src_w as f64;
+
+    // Collapse the map onto the axis the window can move along
+    let horizontal = free_x >= free_y;
+    let (len, window, free) = if horizontal {
+        (small_w, crop_w, free_x)
+    } else {
+        (small_h, crop_h, free_y)
+    };
+    let mut profile = vec![0.0f32; len as usize];
+    for y in 0..small_h {
+        for x in 0..small_w {
+            let i = if horizontal { x } else { y };
+            profile[i as usize] += saliency[(y * small_w + x) as usize];
+        }
+    }
+
+    let window = ((window as f64 * scale).round() as usize).clamp(1, len as usize);
+    let mut best_start = (len as usize - window) / 2;
+    let mut best_score = f32::MIN;
+    for start in 0..=(len as usize - window) {
+        let score: f32 = profile[start..start + window]
+            .iter()
+            .enumerate()
+            .map(|(i, value)| value * importance(i, window))
+            .sum();
+        if score > best_score {
+            best_score = score;
+            best_start = start;
+        }
+    }
+
+    let offset = ((best_start as f64 / scale).round() as u32).min(free);
+    if horizontal {
+        (offset, free_y / 2)
+    } else {
+        (free_x / 2, offset)
+    }
+}
+
+/// Weights positions near the middle of the window twice as much as its edges,
+/// so subjects aren't pushed against the crop boundary.
+fn importance(i: usize, window: usize) -> f32 {
+    let t = (i as f32 + 0.5) / window as f32 * 2.0 - 1.0;
+    1.0 - 0.5 * t * t
+}
+
+fn saliency_map(img: &RgbImage) -> Vec<f32> {
+    let (width, height) = img.dimensions();
+    let luma = image::imageops::grayscale(img);
+    let entropy = entropy_cells(&luma);
+    let cells_x = width.div_ceil(ENTROPY_CELL);
+
+    let mut map = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let edge = edge_strength(&luma, x, y);
+            let cell = (y / ENTROPY_CELL) * cells_x + x / ENTROPY_CELL;
+            let skin = skin_likelihood(img.get_pixel(x, y).0, luma.get_pixel(x, y).0[0]);
+            map.push(
+                EDGE_WEIGHT * edge + ENTROPY_WEIGHT * entropy[cell as usize] + SKIN_WEIGHT * skin,
+            );
+        }
+    }
+
+    map
+}
+
+/// Absolute Laplacian of the luma channel, scaled to roughly 0..1.
+fn edge_strength(luma: &GrayImage, x: u32, y: u32) -> f32 {
+    let (width, height) = luma.dimensions();
+    let at = |x: u32, y: u32| luma.get_pixel(x, y).0[0] as f32;
+    let center = at(x, y);
+    let left = at(x.saturating_sub(1), y);
+    let right = at((x + 1).min(width - 1), y);
+    let up = at(x, y.saturating_sub(1));
+    let down = at(x, (y + 1).min(height - 1));
+
+    let laplacian = (4.0 * center - left - right - up - down).abs();
+    (laplacian / 64.0).min(1.0)
+}
+
+/// Shannon entropy of a 16-bin luma histogram per cell, normalized to 0..1.
+fn entropy_cells(luma: &GrayImage) -> Vec<f32> {
+    let (width, height) = luma.dimensions();
+    let cells_x = width.div_ceil(ENTROPY_CELL);
+    let cells_y = height.div_ceil(ENTROPY_CELL);
+
+    let mut cells = Vec::with_capacity((cells_x * cells_y) as usize);
+    for cy in 0..cells_y {
+        for cx in 0..cells_x {
+            let mut histogram = [0u32; 16];
+            let mut count = 0;
+            for y in (cy * ENTROPY_CELL)..((cy + 1) * ENTROPY_CELL).min(height) {
+                for x in (cx * ENTROPY_CELL)..((cx + 1) * ENTROPY_CELL).min(width) {
+                    histogram[(luma.get_pixel(x, y).0[0] >> 4) as usize] += 1;
+                    count += 1;
+                }
+            }
+
+            let entropy: f32 = histogram
+                .iter()
+                .filter(|&&n| n > 0)
+                .map(|&n| {
+                    let p = n as f32 / count as f32;
+                    -p * p.log2()
+                })
+                .sum();
+            cells.push(entropy / 4.0);
+        }
+    }
+
+    cells
+}
+
+/// How close the pixel's chromaticity is to a typical skin tone, ignoring very
+/// dark pixels.
+fn skin_likelihood(rgb: [u8; 3], luma: u8) -> f32 {
+    if luma < 50 {
+        return 0.0;
+    }
+
+    let [r, g, b] = rgb.map(|c| c as f32);
+    let magnitude = (r * r + g * g + b * b).sqrt();
+    if magnitude == 0.0 {
+        return 0.0;
+    }
+
+    let distance = [r, g, b]
+        .iter()
+        .zip(SKIN_COLOR)
+        .map(|(c, skin)| (c / magnitude - skin).powi(2))
+        .sum::<f32>()
+        .sqrt();
+    let similarity = 1.0 - distance;
+    if similarity < SKIN_THRESHOLD {
+        return 0.0;
+    }
+
+    (similarity - SKIN_THRESHOLD) / (1.0 - SKIN_THRESHOLD)
+}
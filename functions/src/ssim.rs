@@ -0,0 +1,180 @@
+use image::DynamicImage;
+
+/// Weights of each scale, from full resolution down, as in MS-SSIM.
+const SCALE_WEIGHTS: [f32; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+/// Weights of the luma and two chroma channels. Chroma starts at half resolution,
+/// since lossy encoders subsample it and the eye doesn't resolve it any finer.
+const CHANNEL_WEIGHTS: [f32; 3] = [0.8, 0.1, 0.1];
+/// Standard deviation of the Gaussian window, and its radius in pixels.
+const WINDOW_SIGMA: f32 = 1.5;
+const WINDOW_RADIUS: usize = 5;
+/// Stabilizing constants for values in 0.0..=1.0.
+const C1: f32 = 0.01 * 0.01;
+const C2: f32 = 0.03 * 0.03;
+/// Scales smaller than this in either dimension are skipped.
+const MIN_SCALE_SIZE: usize = 8;
+
+/// A reference image prepared for comparing encoded versions of it against.
+pub struct Reference {
+    /// Per scale, per channel: pixels, window means and window variances.
+    scales: Vec<Vec<Option<Statistics>>>,
+}
+
+struct Plane {
+    width: usize,
+    height: usize,
+    pixels: Vec<f32>,
+}
+
+struct Statistics {
+    plane: Plane,
+    mean: Vec<f32>,
+    variance: Vec<f32>,
+}
+
+impl Reference {
+    pub fn new(img: &DynamicImage) -> Self {
+        let scales = pyramid(img)
+            .into_iter()
+            .map(|channels| {
+                channels
+                    .into_iter()
+                    .map(|plane| {
+                        plane.map(|plane| {
+                            let mean = blur(&plane, &plane.pixels);
+                            let squares: Vec<f32> = plane.pixels.iter().map(|x| x * x).collect();
+                            let variance = blur(&plane, &squares)
+                                .iter()
+                                .zip(&mean)
+                                .map(|(square, mean)| square - mean * mean)
+                                .collect();
+                            Statistics { plane, mean, variance }
+                        })
+                    })
+                    .collect()
+            })
+            .collect();
+        Reference { scales }
+    }
+
+    /// Structural dissimilarity of `img` from the reference, `1 / SSIM - 1`: 0 for
+    /// identical images, growing without bound as they diverge. Both must have the
+    /// same dimensions.
+    pub fn dssim(&self, img: &DynamicImage) -> f64 {
+        let mut total = 0.0;
+        let mut total_weight = 0.0;
+        for (scale, (reference, candidate)) in self.scales.iter().zip(pyramid(img)).enumerate() {
+            for (channel, (reference, candidate)) in reference.iter().zip(candidate).enumerate() {
+                let (Some(reference), Some(candidate)) = (reference, candidate) else {
+                    continue;
+                };
+                let weight = SCALE_WEIGHTS[scale] * CHANNEL_WEIGHTS[channel];
+                total += weight * mean_ssim(reference, &candidate);
+                total_weight += weight;
+            }
+        }
+
+        let ssim = (total / total_weight).max(f32::EPSILON) as f64;
+        1.0 / ssim - 1.0
+    }
+}
+
+fn mean_ssim(reference: &Statistics, candidate: &Plane) -> f32 {
+    let mean = blur(candidate, &candidate.pixels);
+    let squares: Vec<f32> = candidate.pixels.iter().map(|y| y * y).collect();
+    let products: Vec<f32> = reference.plane.pixels.iter().zip(&candidate.pixels).map(|(x, y)| x * y).collect();
+    let squares = blur(candidate, &squares);
+    let products = blur(candidate, &products);
+
+    let mut sum = 0.0f64;
+    for i in 0..mean.len() {
+        let (mean_x, mean_y) = (reference.mean[i], mean[i]);
+        let variance_y = squares[i] - mean_y * mean_y;
+        let covariance = products[i] - mean_x * mean_y;
+        let ssim = ((2.0 * mean_x * mean_y + C1) * (2.0 * covariance + C2))
+            / ((mean_x * mean_x + mean_y * mean_y + C1) * (reference.variance[i] + variance_y + C2));
+        sum += ssim as f64;
+    }
+    (sum / mean.len() as f64) as f32
+}
+
+/// Splits the image into Y'CbCr planes and halves them for each scale. Alpha is
+/// composited over mid gray, so differences hidden by transparency don't count.
+fn pyramid(img: &DynamicImage) -> Vec<[Option<Plane>; 3]> {
+    let rgba = img.to_rgba8();
+    let (width, height) = (rgba.width() as usize, rgba.height() as usize);
+    let mut planes: [Plane; 3] = std::array::from_fn(|_| Plane { width, height, pixels: Vec::with_capacity(width * height) });
+    for pixel in rgba.pixels() {
+        let alpha = pixel[3] as f32 / 255.0;
+        let [r, g, b] = [0, 1, 2].map(|c| pixel[c] as f32 / 255.0 * alpha + 0.5 * (1.0 - alpha));
+        let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        planes[0].pixels.push(y);
+        planes[1].pixels.push((b - y) / 1.8556 + 0.5);
+        planes[2].pixels.push((r - y) / 1.5748 + 0.5);
+    }
+
+    let mut scales = Vec::new();
+    for scale in 0..SCALE_WEIGHTS.len() {
+        if planes[0].width < MIN_SCALE_SIZE || planes[0].height < MIN_SCALE_SIZE {
+            break;
+        }
+        let next = planes.each_ref().map(halve);
+        let current = std::mem::replace(&mut planes, next);
+        let [luma, cb, cr] = current;
+        scales.push(match scale {
+            0 => [Some(luma), None, None],
+            _ => [Some(luma), Some(cb), Some(cr)],
+        });
+    }
+    scales
+}
+
+/// Averages 2x2 blocks, dropping the last row or column of odd-sized planes.
+fn halve(plane: &Plane) -> Plane {
+    let (width, height) = (plane.width / 2, plane.height / 2);
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let top = &plane.pixels[2 * y * plane.width..];
+        let bottom = &plane.pixels[(2 * y + 1) * plane.width..];
+        for x in 0..width {
+            pixels.push((top[2 * x] + top[2 * x + 1] + bottom[2 * x] + bottom[2 * x + 1]) / 4.0);
+        }
+    }
+    Plane { width, height, pixels }
+}
+
+/// Separable Gaussian blur of `values` laid out like `plane`, clamping at the edges.
+fn blur(plane: &Plane, values: &[f32]) -> Vec<f32> {
+    let kernel: Vec<f32> = {
+        let raw: Vec<f32> = (0..=2 * WINDOW_RADIUS)
+            .map(|i| {
+                let d = i as f32 - WINDOW_RADIUS as f32;
+                (-d * d / (2.0 * WINDOW_SIGMA * WINDOW_SIGMA)).exp()
+            })
+            .collect();
+        let sum: f32 = raw.iter().sum();
+        raw.iter().map(|k| k / sum).collect()
+    };
+    let (width, height) = (plane.width, plane.height);
+    let tap = |i: usize, k: usize, size: usize| (i + k).saturating_sub(WINDOW_RADIUS).min(size - 1);
+
+    let mut horizontal = vec![0.0; values.len()];
+    for y in 0..height {
+        let row = &values[y * width..(y + 1) * width];
+        for x in 0..width {
+            horizontal[y * width + x] = kernel.iter().enumerate().map(|(k, w)| w * row[tap(x, k, width)]).sum();
+        }
+    }
+
+    let mut vertical = vec![0.0; values.len()];
+    for y in 0..height {
+        for x in 0..width {
+            vertical[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(k, w)| w * horizontal[tap(y, k, height) * width + x])
+                .sum();
+        }
+    }
+    vertical
+}
@@ -0,0 +1,118 @@
+use anyhow::{bail, Context, Result};
+use image::{DynamicImage, RgbaImage};
+use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg::{self, fontdb, ImageHrefResolver};
+use std::sync::{Arc, OnceLock};
+
+/// Largest raster an SVG is rendered to, 64 MB of RGBA. A few bytes of markup can
+/// declare any size, so this keeps a `width="30000"` file from exhausting memory.
+const MAX_PIXELS: f64 = 4096.0 * 4096.0;
+
+/// An SVG source. Variants are rasterized from the vector data at their own
+/// size rather than resized from a bitmap.
+pub struct VectorImage {
+    data: Vec<u8>,
+    /// Size of the SVG's own coordinate space.
+    intrinsic: (f32, f32),
+    /// The intrinsic size, scaled down to fit `MAX_PIXELS`.
+    width: f64,
+    height: f64,
+}
+
+impl VectorImage {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let tree = parse_tree(bytes)?;
+        let size = tree.size();
+        let (width, height) = (size.width() as f64, size.height() as f64);
+        let (width, height) = match (MAX_PIXELS / (width * height)).sqrt() {
+            scale if scale < 1.0 => ((width * scale).floor(), (height * scale).floor()),
+            _ => (width, height),
+        };
+        Ok(VectorImage {
+            data: bytes.to_vec(),
+            intrinsic: (size.width(), size.height()),
+            width,
+            height,
+        })
+    }
+
+    /// Intrinsic size from the `width`/`height` or `viewBox` attributes, scaled
+    /// down when it is larger than the raster size limit.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (
+            (self.width.floor() as u32).max(1),
+            (self.height.floor() as u32).max(1),
+        )
+    }
+
+    /// Size of the SVG's own coordinate space, which the upload's pixel values
+    /// (such as a focal hint) refer to.
+    pub fn intrinsic_size(&self) -> (f64, f64) {
+        (self.intrinsic.0 as f64, self.intrinsic.1 as f64)
+    }
+
+    /// Rasterizes the image at `scale` times its [`dimensions`](Self::dimensions).
+    /// Fails rather than allocating a raster larger than the size limit.
+    pub fn render(&self, scale: f64) -> Result<DynamicImage> {
+        let width = ((self.width * scale).round() as u32).max(1);
+        let height = ((self.height * scale).round() as u32).max(1);
+        if width as f64 * height as f64 > MAX_PIXELS {
+            bail!("SVG raster of {}x{} exceeds the {} pixel limit", width, height, MAX_PIXELS);
+        }
+
+        let tree = parse_tree(&self.data)?;
+        let mut pixmap = Pixmap::new(width, height).context("SVG raster size is invalid")?;
+        let transform = Transform::from_scale(
+            width as f32 / self.intrinsic.0,
+            height as f32 / self.intrinsic.1,
+        );
+        resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+        // tiny-skia stores premultiplied alpha
+        let pixels = pixmap
+            .pixels()
+            .iter()
+            .flat_map(|pixel| {
+                let color = pixel.demultiply();
+                [color.red(), color.green(), color.blue(), color.alpha()]
+            })
+            .collect();
+        let img = RgbaImage::from_raw(width, height, pixels).context("SVG raster size mismatch")?;
+        Ok(DynamicImage::ImageRgba8(img))
+    }
+}
+
+/// Cheap check for SVG markup, allowing for an XML declaration, comments or a
+/// doctype before the root element.
+pub fn is_svg(bytes: &[u8]) -> bool {
+    let head = &bytes[..bytes.len().min(1024)];
+    let text = String::from_utf8_lossy(head);
+    let text = text.trim_start_matches('\u{feff}').trim_start();
+    text.starts_with('<') && text.contains("<svg")
+}
+
+fn parse_tree(bytes: &[u8]) -> Result<usvg::Tree> {
+    let options = usvg::Options {
+        fontdb: fonts(),
+        // Only embedded data: URLs, never files on the function's filesystem
+        image_href_resolver: ImageHrefResolver {
+            resolve_data: ImageHrefResolver::default_data_resolver(),
+            resolve_string: Box::new(|_, _| None),
+        },
+        ..usvg::Options::default()
+    };
+
+    usvg::Tree::from_data(bytes, &options).context("Failed to parse SVG")
+}
+
+/// System fonts for `<text>` elements, loaded once per execution environment.
+fn fonts() -> Arc<fontdb::Database> {
+    static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut database = fontdb::Database::new();
+            database.load_system_fonts();
+            Arc::new(database)
+        })
+        .clone()
+}